@@ -1,178 +1,4677 @@
-use clap::Parser;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{
+    DateTime, FixedOffset, NaiveDate, NaiveTime, SecondsFormat, Utc, format::StrftimeItems,
+};
+use clap::{CommandFactory, Parser, ValueEnum};
+use serde::{Serialize, Serializer};
 use std::{
-    collections::HashMap,
-    fs::{File, read_to_string},
-    io::Write,
-    path::Path,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+    fs::{File, copy, create_dir_all, metadata, read_to_string, write},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Cursor, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::LazyLock,
+};
+use syntect::{
+    highlighting::ThemeSet,
+    html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
 };
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
 
 #[derive(Parser)]
 #[command(name = "StaV", about = "Stack-based composition system")]
 struct Cli {
-    /// Source code file path
-    path: String,
+    /// Source code file paths, where `-` reads the standard input as no path does when it's piped
+    paths: Vec<String>,
+    /// Output file path, or directory to write the output files into, such as `dist/`
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+    /// Write the output to the standard output instead of a file, for piping into other tools
+    #[arg(long, conflicts_with = "output")]
+    stdout: bool,
+    /// Don't evaluate the standard prelude before the source code
+    #[arg(long)]
+    no_prelude: bool,
+    /// Treat warnings, leftover values and insecure links as errors
+    #[arg(long)]
+    strict: bool,
+    /// Define a variable before evaluation, e.g. `--define title=Hello`
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+    defines: Vec<(String, String)>,
+    /// Don't emit the viewport meta tag for mobile devices
+    #[arg(long)]
+    no_viewport: bool,
+    /// Write compact HTML instead of indented one
+    #[arg(long)]
+    minify: bool,
+    /// Load every image lazily as with the `lazy` command
+    #[arg(long)]
+    lazy_images: bool,
+    /// Open every off-site link in a new tab as with the `new-tab` command
+    #[arg(long)]
+    new_tab: bool,
+    /// Format of the output document
+    #[arg(long, value_enum, default_value_t = Format::Html)]
+    format: Format,
+    /// Write a single HTML file with the theme and the local images embedded
+    #[arg(long)]
+    bundle: bool,
+    /// HTML file of the page shell, whose `{{title}}`, `{{lang}}`, `{{theme}}`, `{{head}}` and
+    /// `{{body}}` are filled in
+    #[arg(long, value_name = "PATH")]
+    template: Option<String>,
+    /// Write only the body content, to be embedded in another page
+    #[arg(long)]
+    fragment: bool,
+    /// Present the document as slides, split at headings and `slide-break`
+    #[arg(long)]
+    slides: bool,
+    /// Write an Atom feed with this title listing the documents to `feed.xml`
+    #[arg(long, value_name = "TITLE")]
+    feed: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Html,
+    /// CommonMark with the GitHub extensions
+    Markdown,
+    Latex,
+    /// E-book with a chapter from each top-level heading
+    Epub,
+    /// Plain text wrapped for emails and terminals
+    Text,
+    /// Evaluated document model for other tools to post-process
+    Json,
+    /// Gemini gemtext, with the links on lines of their own
+    Gemtext,
+    /// Word document
+    Docx,
+    /// Manual page of section 1 in roff
+    Man,
+    /// DocBook article
+    Docbook,
+}
+
+impl Format {
+    fn backend(self) -> &'static dyn Backend {
+        match self {
+            Format::Html => &HtmlBackend,
+            Format::Markdown => &MarkdownBackend,
+            Format::Latex => &LatexBackend,
+            Format::Epub => &EpubBackend,
+            Format::Text => &TextBackend,
+            Format::Json => &JsonBackend,
+            Format::Gemtext => &GemtextBackend,
+            Format::Docx => &DocxBackend,
+            Format::Man => &ManBackend,
+            Format::Docbook => &DocBookBackend,
+        }
+    }
+}
+
+/// Generator of an output format from the evaluated document
+trait Backend {
+    /// Extension of the output file
+    fn extension(&self) -> &'static str;
+    /// Whether the output holds the local files instead of referring to them in `assets/`
+    fn embeds_assets(&self, _options: &Options) -> bool {
+        false
+    }
+    fn generate(&self, stack: Stack, options: &Options) -> Result<Vec<u8>, Error>;
+}
+
+struct HtmlBackend;
+
+impl Backend for HtmlBackend {
+    fn extension(&self) -> &'static str {
+        "html"
+    }
+    fn embeds_assets(&self, options: &Options) -> bool {
+        options.bundle
+    }
+    fn generate(&self, stack: Stack, options: &Options) -> Result<Vec<u8>, Error> {
+        generate(stack, options).map(String::into_bytes)
+    }
+}
+
+struct MarkdownBackend;
+
+impl Backend for MarkdownBackend {
+    fn extension(&self) -> &'static str {
+        "md"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(markdown(stack).into_bytes())
+    }
+}
+
+struct LatexBackend;
+
+impl Backend for LatexBackend {
+    fn extension(&self) -> &'static str {
+        "tex"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(latex(stack).into_bytes())
+    }
+}
+
+struct EpubBackend;
+
+impl Backend for EpubBackend {
+    fn extension(&self) -> &'static str {
+        "epub"
+    }
+    fn embeds_assets(&self, _options: &Options) -> bool {
+        true
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        epub(stack)
+    }
+}
+
+struct TextBackend;
+
+impl Backend for TextBackend {
+    fn extension(&self) -> &'static str {
+        "txt"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(plain_text(stack).into_bytes())
+    }
+}
+
+struct JsonBackend;
+
+impl Backend for JsonBackend {
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(json(stack))
+    }
+}
+
+struct DocxBackend;
+
+impl Backend for DocxBackend {
+    fn extension(&self) -> &'static str {
+        "docx"
+    }
+    fn embeds_assets(&self, _options: &Options) -> bool {
+        true
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        docx(stack)
+    }
+}
+
+struct ManBackend;
+
+impl Backend for ManBackend {
+    fn extension(&self) -> &'static str {
+        "1"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(man(stack).into_bytes())
+    }
+}
+
+struct DocBookBackend;
+
+impl Backend for DocBookBackend {
+    fn extension(&self) -> &'static str {
+        "xml"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(docbook(stack).into_bytes())
+    }
+}
+
+struct GemtextBackend;
+
+impl Backend for GemtextBackend {
+    fn extension(&self) -> &'static str {
+        "gmi"
+    }
+    fn generate(&self, stack: Stack, _options: &Options) -> Result<Vec<u8>, Error> {
+        Ok(gemtext(stack).into_bytes())
+    }
+}
+
+fn parse_define(define: &str) -> Result<(String, String), String> {
+    match define.split_once('=') {
+        Some((name, value)) if name.chars().all(is_name_char) => {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, found `{define}`")),
+    }
+}
+
+/// Settings of a compilation given from the command line
+struct Options {
+    prelude: bool,
+    strict: bool,
+    defines: Vec<(String, String)>,
+    viewport: bool,
+    minify: bool,
+    lazy_images: bool,
+    new_tab: bool,
+    format: Format,
+    bundle: bool,
+    /// Page shell replacing the built-in one
+    template: Option<String>,
+    fragment: bool,
+    slides: bool,
+}
+
+fn main() -> ExitCode {
+    let mut cli = Cli::parse();
+    if cli.paths.is_empty() {
+        if std::io::stdin().is_terminal() {
+            let message = "no source file given, and nothing piped to the standard input";
+            Cli::command()
+                .error(clap::error::ErrorKind::MissingRequiredArgument, message)
+                .exit();
+        }
+        cli.paths.push("-".to_owned());
+    }
+    let template = match cli.template.map(read_to_string) {
+        Some(Ok(template)) => Some(template),
+        Some(Err(_)) => {
+            eprintln!("Failed to read template file");
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    let options = Options {
+        prelude: !cli.no_prelude,
+        strict: cli.strict,
+        defines: cli.defines,
+        viewport: !cli.no_viewport,
+        minify: cli.minify,
+        lazy_images: cli.lazy_images,
+        new_tab: cli.new_tab,
+        format: cli.format,
+        bundle: cli.bundle,
+        template,
+        fragment: cli.fragment,
+        slides: cli.slides,
+    };
+    // Several outputs can only go into a directory
+    let into_dir = cli.output.as_ref().is_some_and(|output| {
+        cli.paths.len() > 1
+            || output.ends_with(std::path::is_separator)
+            || Path::new(output).is_dir()
+    });
+    let extension = options.format.backend().extension();
+    let output_paths: Vec<PathBuf> = (cli.paths.iter())
+        .map(|path| {
+            // The standard input has no name to take, so it makes the index page
+            let own = match path.as_str() {
+                "-" => Path::new("index").with_extension(extension),
+                _ => Path::new(path).with_extension(extension),
+            };
+            match &cli.output {
+                Some(dir) if into_dir => Path::new(dir).join(own.file_name().unwrap_or_default()),
+                Some(output) => PathBuf::from(output),
+                None => own,
+            }
+        })
+        .collect();
+    let targets: Vec<Option<&Path>> = (cli.paths.iter().zip(&output_paths))
+        .map(|(path, output_path)| {
+            // What comes from the standard input goes to the standard output unless told otherwise
+            let piped = path == "-" && cli.output.is_none();
+            (!cli.stdout && !piped).then_some(output_path.as_path())
+        })
+        .collect();
+    // Sources of the same name in different directories would overwrite each other's output
+    let mut sources = HashMap::new();
+    for (path, target) in cli.paths.iter().zip(&targets) {
+        let Some(target) = target else {
+            continue;
+        };
+        if let Some(other) = sources.insert(*target, path) {
+            let target = target.display();
+            eprintln!("Failed to write both {other} and {path} to {target}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let mut cache = Cache::default();
+    let (mut entries, mut failures) = (Vec::new(), Vec::new());
+    for (path, output_path) in cli.paths.iter().zip(targets) {
+        match build(Path::new(path), output_path, &options, &mut cache) {
+            Some(entry) => entries.push(entry),
+            None => failures.push(path.as_str()),
+        }
+    }
+    if cli.paths.len() > 1 {
+        let compiled = cli.paths.len() - failures.len();
+        match failures.is_empty() {
+            true => eprintln!("Compiled {compiled} files"),
+            false => eprintln!(
+                "Compiled {compiled} files, {} failed: {}",
+                failures.len(),
+                failures.join(", ")
+            ),
+        }
+    }
+    if let Some(title) = cli.feed {
+        // The feed lies next to the first document, which the links are relative to
+        let dir = output_paths[0].parent().unwrap_or(Path::new(""));
+        if write(dir.join("feed.xml"), atom(&title, entries)).is_err() {
+            eprintln!("Failed to write out the feed");
+            return ExitCode::FAILURE;
+        }
+    }
+    match failures.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+/// Work shared between the documents compiled in one run
+#[derive(Default)]
+struct Cache {
+    /// Stack left by the prelude and the definitions, which each document starts from
+    stack: Option<Stack>,
+    /// Tokens of the included files, as how they expand depends on the macros defined before
+    includes: HashMap<PathBuf, (Vec<Token>, Vec<Warning>)>,
+}
+
+/// Compile the source file into the output file, or the standard output if there's none,
+/// returning its entry in the feed
+fn build(
+    filename: &Path,
+    output_path: Option<&Path>,
+    options: &Options,
+    cache: &mut Cache,
+) -> Option<Entry> {
+    macro_rules! fault {
+        ($msg: literal) => {
+            eprintln!("Failed to {}", $msg);
+            return None;
+        };
+    }
+
+    let source = match filename == Path::new("-") {
+        true => std::io::read_to_string(std::io::stdin()),
+        false => read_to_string(filename),
+    };
+    let Ok(source) = source else {
+        fault!("read source file");
+    };
+    // Messages name the standard input as such, and includes are resolved from the working directory
+    let filename = match filename == Path::new("-") {
+        true => Path::new("<stdin>"),
+        false => filename,
+    };
+    let dir = filename.parent().unwrap_or(Path::new(""));
+    let (mut warnings, mut assets) = (Vec::new(), Vec::new());
+    let result = stav(&source, dir, options, cache, &mut warnings, &mut assets);
+    for warning in warnings {
+        let path = warning.path.as_deref().unwrap_or(filename);
+        eprintln!("{}:{warning}", path.display());
+    }
+    let (output, mut entry) = match result {
+        Ok(output) => output,
+        Err(error) => {
+            eprintln!("Failed to compile StaV code");
+            eprintln!("{}:{error}", filename.display());
+            return None;
+        }
+    };
+    // The assets are copied next to the output file, where its links lead,
+    // or next to the source file without one
+    let out_dir = match output_path {
+        Some(output_path) => output_path.parent().unwrap_or(Path::new("")),
+        None => dir,
+    };
+    if let Some(output_path) = output_path {
+        if create_dir_all(out_dir).is_err() {
+            fault!("create output directory");
+        }
+        let Ok(mut output_file) = File::create(output_path) else {
+            fault!("create output file");
+        };
+        let Ok(_) = output_file.write_all(&output) else {
+            fault!("write out to the file");
+        };
+    } else if std::io::stdout().write_all(&output).is_err() {
+        fault!("write out to the standard output");
+    }
+    if !assets.is_empty() && create_dir_all(out_dir.join("assets")).is_err() {
+        fault!("create assets directory");
+    }
+    for (source, name) in assets {
+        let target = out_dir.join(name);
+        if source.canonicalize().ok() == target.canonicalize().ok() {
+            continue;
+        }
+        if copy(&source, &target).is_err() {
+            eprintln!("Failed to copy {}", source.display());
+        }
+    }
+
+    let extension = options.format.backend().extension();
+    let name =
+        (output_path.map(Path::to_path_buf)).unwrap_or_else(|| filename.with_extension(extension));
+    let name = name.file_name().unwrap_or_default().to_string_lossy();
+    entry.url.get_or_insert_with(|| name.into_owned());
+    let stem = filename.file_stem().unwrap_or_default().to_string_lossy();
+    entry.title.get_or_insert_with(|| stem.into_owned());
+    let modified = metadata(filename).and_then(|metadata| metadata.modified());
+    entry.updated = entry
+        .published
+        .or(modified.ok().map(|time| DateTime::<Utc>::from(time).into()));
+    Some(entry)
+}
+
+/// Document listed in the feed
+struct Entry {
+    title: Option<String>,
+    /// Address the document is published at, or else the path of its output file
+    url: Option<String>,
+    published: Option<DateTime<FixedOffset>>,
+    /// Date of the publication, or else of the last change to the source file
+    updated: Option<DateTime<FixedOffset>>,
+    description: Option<String>,
+    author: Option<String>,
+}
+
+/// Atom feed of the documents, from the newest one
+fn atom(title: &str, mut entries: Vec<Entry>) -> String {
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.updated));
+    let date = |date: DateTime<FixedOffset>| date.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let updated = entries.iter().filter_map(|entry| entry.updated).max();
+    let mut feed = vec![
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>".to_owned(),
+        "<feed xmlns=\"http://www.w3.org/2005/Atom\">".to_owned(),
+        format!("  <title>{}</title>", html_escape(title)),
+        format!("  <id>{}</id>", uuid_urn(title)),
+        format!(
+            "  <updated>{}</updated>",
+            date(updated.unwrap_or_else(|| Utc::now().into()))
+        ),
+    ];
+    for entry in entries {
+        let url = entry.url.unwrap_or_default();
+        let id = match url.contains("://") {
+            true => html_escape(&url),
+            false => uuid_urn(&url),
+        };
+        feed.push("  <entry>".to_owned());
+        let title = entry.title.unwrap_or_default();
+        feed.push(format!("    <title>{}</title>", html_escape(&title)));
+        feed.push(format!("    <link href=\"{}\"/>", html_escape(&url)));
+        feed.push(format!("    <id>{id}</id>"));
+        if let Some(published) = entry.published {
+            feed.push(format!("    <published>{}</published>", date(published)));
+        }
+        let updated = entry.updated.unwrap_or_else(|| Utc::now().into());
+        feed.push(format!("    <updated>{}</updated>", date(updated)));
+        if let Some(author) = entry.author {
+            let name = html_escape(&author);
+            feed.push(format!("    <author><name>{name}</name></author>"));
+        }
+        if let Some(description) = entry.description {
+            feed.push(format!(
+                "    <summary>{}</summary>",
+                html_escape(&description)
+            ));
+        }
+        feed.push("  </entry>".to_owned());
+    }
+    feed.push("</feed>".to_owned());
+    feed.join("\n") + "\n"
+}
+
+const PRELUDE: &str = include_str!("prelude.stav");
+
+fn stav(
+    source: &str,
+    dir: &Path,
+    options: &Options,
+    cache: &mut Cache,
+    warnings: &mut Vec<Warning>,
+    assets: &mut Vec<(PathBuf, String)>,
+) -> Result<(Vec<u8>, Entry), Error> {
+    let mut stack = match &cache.stack {
+        Some(stack) => stack.clone(),
+        None => {
+            let stack = prepare(options)?;
+            cache.stack.insert(stack).clone()
+        }
+    };
+    stack.dir = dir.to_path_buf();
+    stack.includes = std::mem::take(&mut cache.includes);
+    let result = run(source, &mut stack);
+    cache.includes = std::mem::take(&mut stack.includes);
+    let used = &stack.used;
+    stack.warnings.retain(|warning| match &warning.kind {
+        WarningKind::Unused(name) => !used.contains(name),
+        _ => true,
+    });
+    if result.is_ok() {
+        for value in std::mem::take(&mut stack.data) {
+            match value {
+                Value::Text(_) => stack.data.push(value),
+                _ => stack.warn(WarningKind::Leftover(value.type_name())),
+            }
+        }
+    }
+    warnings.append(&mut stack.warnings);
+    result?;
+    if options.strict && !warnings.is_empty() {
+        return Err(ErrorKind::Strict(warnings.len()).into());
+    }
+    let backend = options.format.backend();
+    if !backend.embeds_assets(options) {
+        assets.append(&mut stack.assets);
+    }
+    let meta = |name: &str| {
+        let meta = stack.meta.iter().find(|(meta, _)| meta == name);
+        meta.map(|(_, content)| content.clone())
+    };
+    let entry = Entry {
+        title: stack.title.clone(),
+        url: stack.canonical.clone(),
+        published: stack.published,
+        updated: None,
+        description: meta("description"),
+        author: meta("author"),
+    };
+    Ok((backend.generate(stack, options)?, entry))
+}
+
+/// Stack with the prelude and the definitions from the command line, before any source code
+fn prepare(options: &Options) -> Result<Stack, Error> {
+    let mut stack = Stack {
+        data: Vec::new(),
+        scope: HashMap::new(),
+        title: None,
+        theme: None,
+        meta: Vec::new(),
+        canonical: None,
+        published: None,
+        favicon: None,
+        lang: None,
+        twitter_card: None,
+        dir: PathBuf::new(),
+        assets: Vec::new(),
+        references: Vec::new(),
+        macros: HashMap::new(),
+        constants: HashSet::new(),
+        used: HashSet::new(),
+        warnings: Vec::new(),
+        includes: HashMap::new(),
+        calls: 0,
+        including: Vec::new(),
+    };
+    if options.prelude {
+        run(PRELUDE, &mut stack)?;
+        stack.warnings.clear();
+    }
+    for (name, value) in &options.defines {
+        let value = match Value::parse(value) {
+            Some(Value::Integer(int)) => Value::Integer(int),
+            Some(Value::Bool(bool)) => Value::Bool(bool),
+            Some(Value::Link(url)) => Value::Link(url),
+            _ => Value::Text(Text::new(value.clone())),
+        };
+        stack.scope.insert(name.clone(), value);
+    }
+    Ok(stack)
+}
+
+fn run(source: &str, stack: &mut Stack) -> Result<(), Error> {
+    let tokens = tokenize(source, &mut stack.warnings)?;
+    // Nested calls take more stack than the main thread has, in unoptimized builds above all
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().stack_size(EVAL_STACK_SIZE);
+        let handle = (thread.spawn_scoped(scope, || run_tokens(tokens, stack)))
+            .expect("failed to spawn the thread to evaluate in");
+        handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+fn run_tokens(tokens: Vec<Token>, stack: &mut Stack) -> Result<(), Error> {
+    let mut tokens = expand(tokens, &mut stack.macros, 0)?.into_iter();
+    let exprs = parse(&mut tokens, None)?;
+    Checker::new(stack).check(&exprs)?;
+    for expr in exprs {
+        expr.eval(stack)?;
+    }
+    Ok(())
+}
+
+/// Macro defined by `macro name $param ... [ body ]`
+#[derive(Clone, Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Nesting limit of macro expansions, which catches recursive macros
+const MAX_EXPANSION: usize = 64;
+
+fn expand(
+    tokens: Vec<Token>,
+    macros: &mut HashMap<String, Macro>,
+    depth: usize,
+) -> Result<Vec<Token>, Error> {
+    let mut result = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        let fail = |reason| Error::from(ErrorKind::InvalidMacro(reason)).at(&token);
+        if token.text == "macro" {
+            let name = tokens.next().ok_or_else(|| fail("needs a name"))?;
+            let mut params = Vec::new();
+            loop {
+                match tokens.next() {
+                    Some(param) if param.text == "[" => break,
+                    Some(param) if param.text.starts_with("$") => params.push(param.text),
+                    _ => return Err(fail("needs parameters and a body in brackets")),
+                }
+            }
+            let mut body = take_group(&mut tokens, 1).ok_or_else(|| fail("body is not closed"))?;
+            body.pop();
+            macros.insert(name.text, Macro { params, body });
+        } else if let Some(definition) = macros.get(&token.text).cloned() {
+            if depth >= MAX_EXPANSION {
+                return Err(fail("expands too deeply"));
+            }
+            let mut args = HashMap::new();
+            for param in &definition.params {
+                let arg = take_group(&mut tokens, 0).ok_or_else(|| fail("needs more arguments"))?;
+                args.insert(param, arg);
+            }
+            let mut body = Vec::new();
+            for item in definition.body {
+                match args.get(&item.text) {
+                    Some(arg) => body.extend(arg.iter().cloned()),
+                    None => body.push(Token {
+                        text: item.text,
+                        span: token.span.clone(),
+                    }),
+                }
+            }
+            result.extend(expand(body, macros, depth + 1)?);
+        } else {
+            result.push(token);
+        }
+    }
+    Ok(result)
+}
+
+/// Take a token, or tokens up to the matching bracket when the given nesting is left open
+fn take_group(tokens: &mut impl Iterator<Item = Token>, mut nesting: usize) -> Option<Vec<Token>> {
+    let mut group = Vec::new();
+    loop {
+        let token = tokens.next()?;
+        match token.text.as_str() {
+            "[" | "(" => nesting += 1,
+            "]" | ")" => nesting = nesting.checked_sub(1)?,
+            _ => {}
+        }
+        group.push(token);
+        if nesting == 0 {
+            return Some(group);
+        }
+    }
+}
+
+fn parse(
+    tokens: &mut impl Iterator<Item = Token>,
+    open: Option<&Token>,
+) -> Result<Vec<Expr>, Error> {
+    let mut exprs = Vec::new();
+    while let Some(token) = tokens.next() {
+        let node = match token.text.as_str() {
+            "[" => Node::Literal(Value::Quote(parse(tokens, Some(&token))?)),
+            "(" => Node::List(parse(tokens, Some(&token))?),
+            "]" if open.is_some_and(|open| open.text == "[") => return Ok(exprs),
+            ")" if open.is_some_and(|open| open.text == "(") => return Ok(exprs),
+            "]" | ")" => return Err(Error::from(ErrorKind::UnmatchedBracket).at(&token)),
+            text => Node::parse(text).ok_or(Error::from(ErrorKind::UnknownToken).at(&token))?,
+        };
+        exprs.push(Expr { node, token });
+    }
+    match open {
+        Some(token) => Err(Error::from(ErrorKind::UnmatchedBracket).at(token)),
+        None => Ok(exprs),
+    }
+}
+
+#[derive(Clone)]
+struct Stack {
+    data: Vec<Value>,
+    scope: HashMap<String, Value>,
+    title: Option<String>,
+    theme: Option<String>,
+    /// Name and content of the `<meta>` tags in the head
+    meta: Vec<(String, String)>,
+    /// Address the page is published at
+    canonical: Option<String>,
+    /// Date the page was published at, set by `published`
+    published: Option<DateTime<FixedOffset>>,
+    /// Link or path of the icon
+    favicon: Option<String>,
+    /// Language tag of the document like `ja` or `en-US`
+    lang: Option<String>,
+    /// Kind of the Twitter card, chosen by whether the page has an image if not set
+    twitter_card: Option<String>,
+    /// Directory that relative paths (e.g. of `include`) are resolved against
+    dir: PathBuf,
+    /// Local files to copy next to the HTML and their paths there
+    assets: Vec<(PathBuf, String)>,
+    /// Works cited by `cite`, numbered from 1 in the order of their first citation
+    references: Vec<Text>,
+    macros: HashMap<String, Macro>,
+    /// Names of the variables defined by `const`, which can't be stored again
+    constants: HashSet<String>,
+    /// Names of the variables that have been read at least once
+    used: HashSet<String>,
+    warnings: Vec<Warning>,
+    /// Tokens of the included files and the warnings from reading them, by their canonical path
+    includes: HashMap<PathBuf, (Vec<Token>, Vec<Warning>)>,
+    /// Number of the quotations being called, which catches endless recursion
+    calls: usize,
+    /// Canonical paths of the files being included, which catch include cycles
+    including: Vec<PathBuf>,
+}
+
+/// Nesting limit of quotation calls
+const MAX_CALLS: usize = 256;
+
+/// Stack size of the thread evaluating code, room for `MAX_CALLS` even in unoptimized builds
+const EVAL_STACK_SIZE: usize = 512 << 20;
+
+impl Stack {
+    fn lookup(&mut self, name: &str) -> Result<&Value, Error> {
+        self.used.insert(name.to_string());
+        (self.scope.get(name)).ok_or(ErrorKind::Undefined(name.to_string()).into())
+    }
+
+    /// Evaluate one call deeper, within the nesting limit
+    fn nested(&mut self, f: impl FnOnce(&mut Stack) -> Result<(), Error>) -> Result<(), Error> {
+        if self.calls >= MAX_CALLS {
+            return Err(ErrorKind::Recursion.into());
+        }
+        self.calls += 1;
+        let result = f(self);
+        self.calls -= 1;
+        result
+    }
+
+    /// Evaluate the quotation on the stack as a call
+    fn call(&mut self, body: &[Expr]) -> Result<(), Error> {
+        self.nested(|stack| body.iter().try_for_each(|expr| expr.eval(stack)))
+    }
+
+    /// Evaluate the quotation on an empty stack and take the values it leaves
+    fn collect(&mut self, body: &[Expr]) -> Result<Vec<Value>, Error> {
+        let data = std::mem::take(&mut self.data);
+        let result = self.call(body);
+        let values = std::mem::replace(&mut self.data, data);
+        result.map(|_| values)
+    }
+
+    /// Register the local file as an asset, returning its path relative to the HTML
+    fn asset(&mut self, path: &str) -> Result<String, Error> {
+        let path = self.dir.join(path);
+        std::fs::metadata(&path).map_err(|e| ErrorKind::Io(path.clone(), e))?;
+        if let Some((_, name)) = self.assets.iter().find(|(source, _)| *source == path) {
+            return Ok(name.clone());
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = match path.extension() {
+            Some(extension) => format!(".{}", extension.to_string_lossy()),
+            None => String::new(),
+        };
+        let mut name = format!("assets/{stem}{extension}");
+        let mut count = 0;
+        while self.assets.iter().any(|(_, other)| *other == name) {
+            count += 1;
+            name = format!("assets/{stem}-{count}{extension}");
+        }
+        self.assets.push((path, name.clone()));
+        Ok(name)
+    }
+
+    /// Set the content of the `<meta>` tag, replacing the previous one
+    fn set_meta(&mut self, name: &str, content: String) {
+        match self.meta.iter_mut().find(|(key, _)| key == name) {
+            Some((_, value)) => *value = content,
+            None => self.meta.push((name.to_owned(), content)),
+        }
+    }
+
+    fn warn(&mut self, kind: WarningKind) {
+        self.warnings.push(Warning {
+            kind,
+            token: None,
+            path: None,
+        });
+    }
+}
+
+/// Pop a value off the stack, optionally requiring a specific variant of `Value`
+macro_rules! pop {
+    ($stack: expr) => {
+        $stack.data.pop().ok_or(ErrorKind::StackUnderflow)?
+    };
+    ($stack: expr, $variant: ident) => {
+        match pop!($stack) {
+            Value::$variant(value) => value,
+            value => {
+                return Err(ErrorKind::TypeMismatch {
+                    expected: stringify!($variant),
+                    found: value.type_name(),
+                }
+                .into());
+            }
+        }
+    };
+}
+
+#[derive(Clone, Debug)]
+struct Span {
+    line: usize,
+    column: usize,
+}
+
+impl Span {
+    fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+#[derive(Debug)]
+struct Error {
+    kind: ErrorKind,
+    /// Token that caused the error, if it is known
+    token: Option<Token>,
+}
+
+impl Error {
+    fn at(mut self, token: &Token) -> Error {
+        self.token.get_or_insert_with(|| token.clone());
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error { kind, token: None }
+    }
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Span { line, column } = self.span;
+        let text = self.text.lines().next().unwrap_or_default();
+        let ellipsis = if text.len() < self.text.len() {
+            "..."
+        } else {
+            ""
+        };
+        write!(f, "{line}:{column}: `{text}{ellipsis}`")
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.token {
+            Some(token) => write!(f, "{token} {}", self.kind),
+            None => write!(f, " {}", self.kind),
+        }
+    }
+}
+
+/// Diagnostic that is reported without aborting the compilation
+#[derive(Clone, Debug)]
+struct Warning {
+    kind: WarningKind,
+    token: Option<Token>,
+    /// Included file the warning comes from
+    path: Option<PathBuf>,
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.token {
+            Some(token) => write!(f, "{token} warning: {}", self.kind),
+            None => write!(f, " warning: {}", self.kind),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum WarningKind {
+    UnknownEscape(char),
+    Unused(String),
+    Undefined(String),
+    HeadingLevel(i32),
+    InsecureLink(String),
+    Leftover(&'static str),
+}
+
+impl std::fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WarningKind::UnknownEscape(c) => write!(f, "unknown escape sequence `\\{c}`"),
+            WarningKind::Unused(name) => write!(f, "variable `{name}` is never used"),
+            WarningKind::Undefined(name) => write!(f, "`{name}` is not defined, left as is"),
+            WarningKind::HeadingLevel(level) => {
+                write!(f, "heading level {level} is outside of 1 to 6")
+            }
+            WarningKind::InsecureLink(url) => write!(f, "link {url} is not https"),
+            WarningKind::Leftover(name) => {
+                write!(f, "{name} left on the stack is not rendered")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ErrorKind {
+    UnterminatedString,
+    InvalidEscape,
+    UnknownToken,
+    UnmatchedBracket,
+    StackUnderflow,
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    Undefined(String),
+    Constant(String),
+    Assertion(String),
+    Raised(String),
+    Env(String),
+    InvalidDate(String),
+    InvalidRange(i32, i32),
+    OutOfRange(i32, i32),
+    InvalidDepth(i32),
+    MissingKey(String),
+    InvalidFormat(&'static str),
+    InvalidMacro(&'static str),
+    InvalidAttribute(String),
+    InvalidValue(&'static str, String),
+    Overflow,
+    Recursion,
+    Effect {
+        expected: Vec<Type>,
+        found: Vec<Type>,
+    },
+    Markup(String),
+    Package(zip::result::ZipError),
+    Io(PathBuf, std::io::Error),
+    Include(PathBuf, Box<Error>),
+    IncludeCycle(PathBuf),
+    Strict(usize),
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fn article(name: &str) -> &str {
+            if name.starts_with(['A', 'E', 'I', 'O', 'U']) {
+                "an"
+            } else {
+                "a"
+            }
+        }
+        match self {
+            ErrorKind::UnterminatedString => write!(f, "string literal is not terminated"),
+            ErrorKind::InvalidEscape => write!(f, "invalid escape sequence"),
+            ErrorKind::UnknownToken => write!(f, "is not a command, value or word"),
+            ErrorKind::UnmatchedBracket => write!(f, "bracket is not matched"),
+            ErrorKind::StackUnderflow => write!(f, "needs more values on the stack"),
+            ErrorKind::TypeMismatch { expected, found } => write!(
+                f,
+                "expected {} {expected} on the stack, found {found}",
+                article(expected)
+            ),
+            ErrorKind::Effect { expected, found } => {
+                let list = |types: &[Type]| -> String {
+                    let names: Vec<&str> = types.iter().map(|ty| ty.name()).collect();
+                    format!("[{}]", names.join(" "))
+                };
+                write!(
+                    f,
+                    "expects {} but the stack holds {}",
+                    list(expected),
+                    list(found)
+                )
+            }
+            ErrorKind::Undefined(name) => write!(f, "`{name}` is not defined"),
+            ErrorKind::Assertion(message) => write!(f, "assertion failed: {message}"),
+            ErrorKind::Raised(message) => write!(f, "{message}"),
+            ErrorKind::Env(name) => write!(f, "environment variable `{name}` is not set"),
+            ErrorKind::InvalidDate(text) => write!(f, "`{text}` is not a valid date or pattern"),
+            ErrorKind::Constant(name) => write!(f, "`{name}` is a constant and can't be redefined"),
+            ErrorKind::InvalidRange(start, end) => write!(f, "range {start}..{end} is invalid"),
+            ErrorKind::OutOfRange(index, len) => {
+                write!(f, "index {index} is out of range for a List of {len} items")
+            }
+            ErrorKind::InvalidDepth(depth) => write!(f, "list depth {depth} is less than 1"),
+            ErrorKind::MissingKey(key) => write!(f, "key `{key}` is not in the Map"),
+            ErrorKind::InvalidFormat(reason) => write!(f, "invalid format text: {reason}"),
+            ErrorKind::InvalidMacro(reason) => write!(f, "macro {reason}"),
+            ErrorKind::InvalidValue(kind, value) => write!(f, "`{value}` is not a valid {kind}"),
+            ErrorKind::InvalidAttribute(name) => {
+                write!(f, "`{name}` is not a valid attribute name")
+            }
+            ErrorKind::Overflow => write!(f, "integer overflow"),
+            ErrorKind::Recursion => write!(f, "calls are nested too deeply"),
+            ErrorKind::Markup(reason) => write!(f, "generated HTML is malformed: {reason}"),
+            ErrorKind::Package(error) => write!(f, "cannot package the document: {error}"),
+            ErrorKind::Io(path, error) => write!(f, "cannot read {}: {error}", path.display()),
+            ErrorKind::Include(path, error) => write!(f, "failed\n{}:{error}", path.display()),
+            ErrorKind::IncludeCycle(path) => {
+                write!(f, "{} is already being included", path.display())
+            }
+            ErrorKind::Strict(count) => {
+                write!(f, "aborted due to {count} warning(s) in strict mode")
+            }
+        }
+    }
+}
+
+fn generate(mut stack: Stack, options: &Options) -> Result<String, Error> {
+    let mut html = Html::default();
+    let mut data = std::mem::take(&mut stack.data);
+
+    let mut has_toc = false;
+    visit_texts(&mut data, &mut |text| {
+        has_toc |= matches!(text.tag, HTMLTag::Toc);
+    });
+    let mut image = None;
+    let mut math = false;
+    let mut diagram = false;
+    visit_texts(&mut data, &mut |text| {
+        diagram |= matches!(text.tag, HTMLTag::Diagram);
+        math |= matches!(text.tag, HTMLTag::Math);
+        math |= text
+            .inline
+            .iter()
+            .any(|inline| matches!(inline, Inline::Math));
+        let tag = match &text.tag {
+            HTMLTag::Figure(figure, _) => &figure.tag,
+            tag => tag,
+        };
+        if let HTMLTag::Image(url) = tag
+            && !url.starts_with("data:")
+        {
+            image.get_or_insert_with(|| url.clone());
+        }
+    });
+    if options.lazy_images {
+        visit_texts(&mut data, &mut |text| {
+            lazy(text);
+        });
+    }
+    if options.new_tab {
+        // Links to the host of the canonical address stay on the site
+        let site = stack.canonical.as_deref().and_then(host);
+        visit_texts(&mut data, &mut |text| {
+            if let HTMLTag::Link(url) = &text.tag
+                && host(url).is_some_and(|host| Some(host) != site)
+                && !text
+                    .attributes
+                    .attrs
+                    .iter()
+                    .any(|(name, _)| name == "target")
+            {
+                new_tab(text);
+            }
+        });
+    }
+    if options.bundle {
+        let mut result = Ok(());
+        visit_texts(&mut data, &mut |text| {
+            let tag = match &mut text.tag {
+                HTMLTag::Figure(image, _) => &mut image.tag,
+                tag => tag,
+            };
+            if let HTMLTag::Image(url) = tag
+                && let Some((path, _)) = stack.assets.iter().find(|(_, name)| name == url)
+            {
+                match data_url(path) {
+                    Ok(data) => *url = data,
+                    Err(error) => result = Err(error),
+                }
+            }
+        });
+        result?;
+        if let Some(icon) = &stack.favicon
+            && let Some((path, _)) = stack.assets.iter().find(|(_, name)| name == icon)
+            && image_type(path).is_some()
+        {
+            stack.favicon = Some(data_url(path)?);
+        }
+    }
+    let mut headings = Vec::new();
+    for (level, text) in heading_ids(&mut data) {
+        let id = text.attributes.id.clone().unwrap_or_default();
+        headings.push((level, inline_html(&text), format!("#{id}")));
+    }
+
+    if has_toc {
+        html.toc = toc_html(headings, "ul");
+    }
+    html.bibliography = bibliography_html(&stack.references);
+    let body = if options.slides {
+        let mut slides: Vec<String> = (split_slides(data).into_iter())
+            .map(|slide| html.render(slide))
+            .collect();
+        slides.extend(Some(html.footnotes_html()).filter(|notes| !notes.is_empty()));
+        let slides = slides.iter().enumerate().map(|(index, slide)| {
+            let number = index + 1;
+            format!("<section class=\"slide\" id=\"slide-{number}\">\n{slide}\n</section>")
+        });
+        slides.collect::<Vec<_>>().join("\n")
+    } else {
+        html.render(data) + &html.footnotes_html()
+    };
+    let mut head: Vec<String> = Vec::new();
+    if options.viewport {
+        let viewport = "width=device-width, initial-scale=1";
+        head.push(format!("<meta name=\"viewport\" content=\"{viewport}\">"));
+    }
+    head.extend((stack.meta.iter()).map(|(name, content)| {
+        format!(
+            "<meta name=\"{name}\" content=\"{}\">",
+            html_escape(content)
+        )
+    }));
+    let description = (stack.meta.iter()).find(|(name, _)| name == "description");
+    let open_graph = [
+        ("og:title", stack.title.clone()),
+        (
+            "og:description",
+            description.map(|(_, content)| content.clone()),
+        ),
+        ("og:image", image.clone()),
+        ("og:url", stack.canonical.clone()),
+        (
+            "article:published_time",
+            (stack.published).map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        ),
+    ];
+    for (property, content) in open_graph {
+        if let Some(content) = content {
+            head.push(format!(
+                "<meta property=\"{property}\" content=\"{}\">",
+                html_escape(&content)
+            ));
+        }
+    }
+    if stack.title.is_some() || image.is_some() {
+        let card = (stack.twitter_card.clone()).unwrap_or_else(|| match image {
+            Some(_) => "summary_large_image".to_owned(),
+            None => "summary".to_owned(),
+        });
+        let twitter = [
+            ("twitter:card", Some(card)),
+            ("twitter:title", stack.title.clone()),
+            ("twitter:image", image),
+        ];
+        for (name, content) in twitter {
+            if let Some(content) = content {
+                head.push(format!(
+                    "<meta name=\"{name}\" content=\"{}\">",
+                    html_escape(&content)
+                ));
+            }
+        }
+    }
+    if let Some(icon) = &stack.favicon {
+        head.push(format!(
+            "<link rel=\"icon\" href=\"{}\">",
+            html_escape(icon)
+        ));
+    }
+    if let Some(url) = &stack.canonical {
+        head.push(format!(
+            "<link rel=\"canonical\" href=\"{}\">",
+            html_escape(url)
+        ));
+    }
+    // Scripts and styles which the body needs even as a fragment
+    let mut resources: Vec<String> = Vec::new();
+    if math {
+        resources.push(format!(
+            "<link rel=\"stylesheet\" href=\"{KATEX}/katex.min.css\">\n<script defer src=\"{KATEX}/katex.min.js\" onload=\"{}\"></script>",
+            "document.querySelectorAll('.math').forEach(e => katex.render(e.textContent, e, \
+             { displayMode: e.classList.contains('display'), throwOnError: false }))"
+        ));
+    }
+    if diagram {
+        resources.push(format!(
+            "<script type=\"module\">import mermaid from '{MERMAID}'; mermaid.initialize({{ startOnLoad: true }});</script>"
+        ));
+    }
+    if html.highlighted {
+        resources.push(format!("<style>{}</style>", highlight_css()));
+    }
+    if options.slides {
+        resources.push(format!("<style>{SLIDES_CSS}</style>"));
+    }
+    let html = if options.fragment {
+        resources.push(body);
+        resources.join("\n") + "\n"
+    } else {
+        head.extend(resources);
+        page(&stack, options, head, body)?
+    };
+    validate(&html)?;
+    if options.minify {
+        Ok(minify(&html))
+    } else {
+        Ok(pretty(&html))
+    }
+}
+
+/// Whole page around the body, with the head holding the metadata and resources
+fn page(
+    stack: &Stack,
+    options: &Options,
+    head: Vec<String>,
+    body: String,
+) -> Result<String, Error> {
+    let title = html_escape(stack.title.as_deref().unwrap_or("Untitled"));
+    let theme = stack.theme.as_deref().unwrap_or("none");
+    let theme = match options.bundle {
+        true => format!("<style>{}</style>", theme_css(&stack.dir, theme)?),
+        false => format!(
+            "<link rel=\"stylesheet\" href=\"theme/{}.css\">",
+            html_escape(theme)
+        ),
+    };
+    if let Some(template) = &options.template {
+        let lang = stack.lang.as_deref().map(html_escape).unwrap_or_default();
+        let values = [
+            ("title", title),
+            ("lang", lang),
+            ("theme", theme),
+            ("head", head.join("\n")),
+            ("body", body),
+        ];
+        return Ok(fill(template, &values));
+    }
+    let lang = match &stack.lang {
+        Some(lang) => format!(" lang=\"{}\"", html_escape(lang)),
+        None => String::new(),
+    };
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html{}>
+<head>
+<meta charset="UTF-8">
+<title>{}</title>
+{}
+{}
+</head>
+<body>
+{}
+</body>
+</html>
+"#,
+        lang,
+        title,
+        theme,
+        head.join("\n"),
+        body,
+    );
+    Ok(html)
+}
+
+/// Replace the `{{name}}` placeholders in the template at once, so that values can't fill in
+/// each other, leaving the unknown ones as they are
+fn fill(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result += &rest[..start];
+        rest = &rest[start..];
+        let value = rest.find("}}").and_then(|end| {
+            let name = rest[2..end].trim();
+            let (_, value) = values.iter().find(|(key, _)| *key == name)?;
+            Some((value, end + 2))
+        });
+        match value {
+            Some((value, end)) => {
+                result += value;
+                rest = &rest[end..];
+            }
+            None => {
+                result += "{{";
+                rest = &rest[2..];
+            }
+        }
+    }
+    result + rest
+}
+
+/// Themes shipped with StaV, used when the document's directory has no such theme
+const THEMES: [(&str, &str); 2] = [
+    ("basic", include_str!("../theme/basic.css")),
+    ("none", include_str!("../theme/none.css")),
+];
+
+/// Style sheet of the theme, from the `theme` directory next to the document
+fn theme_css(dir: &Path, name: &str) -> Result<String, Error> {
+    let path = dir.join("theme").join(format!("{name}.css"));
+    match read_to_string(&path) {
+        Ok(css) => Ok(css),
+        Err(error) => match THEMES.iter().find(|(theme, _)| *theme == name) {
+            Some((_, css)) => Ok(css.to_string()),
+            None => Err(ErrorKind::Io(path, error).into()),
+        },
+    }
+}
+
+/// Each slide fills the screen, scrolled one at a time, and is printed on a page of its own
+const SLIDES_CSS: &str = "html { scroll-snap-type: y mandatory; } \
+    .slide { box-sizing: border-box; min-height: 100vh; padding: 4vh 6vw; \
+    display: flex; flex-direction: column; justify-content: center; scroll-snap-align: start; } \
+    @media print { .slide { min-height: auto; break-after: page; } }";
+
+/// Split the values into slides at each `slide-break` and each heading, except one under a heading
+/// which begins the slide
+fn split_slides(values: Vec<Value>) -> Vec<Vec<Value>> {
+    let mut slides: Vec<Vec<Value>> = vec![Vec::new()];
+    for value in values {
+        let tag = match &value {
+            Value::Text(text) => Some(&text.tag),
+            _ => None,
+        };
+        let Some(slide) = slides.last_mut() else {
+            continue;
+        };
+        let only_headings = (slide.iter()).all(|value| {
+            matches!(
+                value,
+                Value::Text(Text {
+                    tag: HTMLTag::Heading(_),
+                    ..
+                })
+            )
+        });
+        match tag {
+            Some(HTMLTag::SlideBreak) => {
+                if !slide.is_empty() {
+                    slides.push(Vec::new());
+                }
+            }
+            Some(HTMLTag::Heading(_)) if !only_headings => slides.push(vec![value]),
+            _ => slide.push(value),
+        }
+    }
+    slides.retain(|slide| !slide.is_empty());
+    slides
+}
+
+/// Nested list of the headings given as level, content and address
+fn toc_html(headings: Vec<(i32, String, String)>, list: &str) -> String {
+    let min = headings.iter().map(|(level, ..)| *level).min().unwrap_or(1);
+    let items = (headings.into_iter())
+        .map(|(level, content, href)| GroupItem {
+            wrapper: list.to_owned(),
+            depth: (level - min) as usize,
+            html: format!("<li><a href=\"{}\">{content}</a>", html_escape(&href)),
+            close: "</li>",
+        })
+        .collect::<Vec<_>>();
+    wrap_group(items)
+}
+
+/// Items of the cited works, numbered in the order of their first citation
+fn bibliography_html(references: &[Text]) -> String {
+    let items = (references.iter().enumerate()).map(|(index, reference)| {
+        format!("<li id=\"ref-{}\">{}</li>", index + 1, span_html(reference))
+    });
+    items.collect::<Vec<_>>().join("\n")
+}
+
+/// Elements which have no closing tag
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose content lines are kept as is, as the whitespace matters
+const RAW_ELEMENTS: [&str; 4] = ["pre", "script", "style", "textarea"];
+
+/// Check that every element of the markup is closed in the order it's opened
+fn validate(html: &str) -> Result<(), ErrorKind> {
+    let mut open: Vec<&str> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let end = tag_end(rest);
+        let tag = rest[1..end].trim_end_matches('>');
+        rest = &rest[end..];
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim();
+            match open.pop() {
+                Some(expected) if expected == name => {}
+                Some(expected) => {
+                    let reason = format!("expected </{expected}> but found </{name}>");
+                    return Err(ErrorKind::Markup(reason));
+                }
+                None => return Err(ErrorKind::Markup(format!("</{name}> closes nothing"))),
+            }
+            continue;
+        }
+        let name = (tag.split(|c: char| !c.is_ascii_alphanumeric()).next()).unwrap_or_default();
+        if name.is_empty() || VOID_ELEMENTS.contains(&name) || tag.ends_with('/') {
+            continue;
+        }
+        if RAW_ELEMENTS.contains(&name) {
+            let close = format!("</{name}>");
+            let Some(index) = rest.find(&close) else {
+                return Err(ErrorKind::Markup(format!("<{name}> is not closed")));
+            };
+            rest = &rest[index + close.len()..];
+            continue;
+        }
+        open.push(name);
+    }
+    match open.pop() {
+        Some(name) => Err(ErrorKind::Markup(format!("<{name}> is not closed"))),
+        None => Ok(()),
+    }
+}
+
+/// Length of the tag at the start, skipping `>` in quoted attribute values
+fn tag_end(tag: &str) -> usize {
+    let mut quote = None;
+    for (index, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if open == c => quote = None,
+            (None, '>') => return index + 1,
+            _ => {}
+        }
+    }
+    tag.len()
+}
+
+/// End tags which HTML allows to omit where the generator places them
+const OPTIONAL_END_TAGS: [&str; 9] = [
+    "</li>", "</dt>", "</dd>", "</tr>", "</td>", "</th>", "</head>", "</body>", "</html>",
+];
+
+/// Collapse the whitespace and drop the optional end tags of the markup
+fn minify(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = tag_end(rest);
+            let tag = &rest[..end];
+            let name = tag[1..].split(|c: char| !c.is_ascii_alphanumeric()).next();
+            // Whitespace in a stylesheet can be collapsed unlike the others
+            let raw = RAW_ELEMENTS.iter().filter(|raw| **raw != "style");
+            if let Some(name) = raw.into_iter().find(|raw| Some(**raw) == name) {
+                // Keep the content of the element as is
+                let close = format!("</{name}>");
+                let end = rest
+                    .find(&close)
+                    .map_or(rest.len(), |index| index + close.len());
+                result += &rest[..end];
+                rest = &rest[end..];
+                continue;
+            }
+            if !OPTIONAL_END_TAGS.contains(&tag) {
+                result += tag;
+            }
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..end];
+            // Line breaks between the elements are only for readability
+            if !(text.trim().is_empty() && text.contains('\n')) {
+                let mut space = false;
+                for c in text.chars() {
+                    if !c.is_whitespace() {
+                        result.push(c);
+                    } else if !space {
+                        result.push(' ');
+                    }
+                    space = c.is_whitespace();
+                }
+            }
+            rest = &rest[end..];
+        }
+    }
+    result
+}
+
+/// Indent each line of the markup by the nesting of the elements
+fn pretty(html: &str) -> String {
+    let mut result = String::new();
+    let mut depth: usize = 0;
+    let mut raw: Option<&str> = None;
+    for line in html.lines() {
+        let tags: Vec<(bool, &str)> = (line.split('<').skip(1))
+            .filter_map(|tag| {
+                let (closing, tag) = match tag.strip_prefix('/') {
+                    Some(tag) => (true, tag),
+                    None => (false, tag),
+                };
+                let end = tag
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(tag.len());
+                let name = &tag[..end];
+                let is_name = name.starts_with(|c: char| c.is_ascii_alphabetic());
+                (is_name && (closing || !VOID_ELEMENTS.contains(&name))).then_some((closing, name))
+            })
+            .collect();
+
+        let trimmed = line.trim();
+        if let Some(element) = raw {
+            result += line;
+            if tags.contains(&(true, element)) {
+                raw = None;
+            }
+        } else if !trimmed.is_empty() {
+            let leading = tags.iter().take_while(|(closing, _)| *closing).count();
+            let leading = if trimmed.starts_with("</") {
+                leading
+            } else {
+                0
+            };
+            result += &"  ".repeat(depth.saturating_sub(leading));
+            result += trimmed;
+            raw = (tags.iter())
+                .filter(|(closing, name)| !closing && RAW_ELEMENTS.contains(name))
+                .map(|(_, name)| *name)
+                .find(|name| !tags.contains(&(true, *name)));
+        } else {
+            continue;
+        }
+        result += "\n";
+
+        let opens = tags.iter().filter(|(closing, _)| !closing).count();
+        depth = (depth + opens).saturating_sub(tags.len() - opens);
+    }
+    result
+}
+
+/// State shared while rendering the body and its nested groups
+#[derive(Default)]
+struct Html {
+    /// Whether any code block was highlighted and needs the stylesheet
+    highlighted: bool,
+    /// Notes in the order of their references, numbered from 1
+    footnotes: Vec<Text>,
+    /// Nested list of the headings, placed by `toc`
+    toc: String,
+    /// Items of the cited works, placed by `bibliography`
+    bibliography: String,
+}
+
+impl Html {
+    /// Section of the notes referenced so far, or nothing if there are none
+    fn footnotes_html(&self) -> String {
+        if self.footnotes.is_empty() {
+            return String::new();
+        }
+        let notes: Vec<String> = (self.footnotes.iter().enumerate())
+            .map(|(index, note)| {
+                let number = index + 1;
+                format!(
+                    "<li id=\"fn-{number}\">{} <a href=\"#fnref-{number}\">\u{21a9}</a></li>",
+                    inline_html(note)
+                )
+            })
+            .collect();
+        format!(
+            "\n<section class=\"footnotes\">\n<hr>\n<ol>\n{}\n</ol>\n</section>",
+            notes.join("\n")
+        )
+    }
+
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output = Vec::new();
+        // Consecutive items sharing a wrapper element, like `<li>` in `<ul>`
+        let mut group: Vec<GroupItem> = Vec::new();
+        for value in values {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let attributes = text.html_attributes();
+            let mut content = inline_html(&text);
+            for note in text.footnotes {
+                self.footnotes.push(note);
+                let number = self.footnotes.len();
+                content += &format!(
+                    "<sup id=\"fnref-{number}\"><a href=\"#fn-{number}\">{number}</a></sup>"
+                );
+            }
+            let depth = text.depth;
+
+            let (wrapper, html) = match text.tag {
+                HTMLTag::Paragraph => (None, format!("<p{attributes}>{content}</p>")),
+                HTMLTag::Heading(level) => {
+                    (None, format!("<h{level}{attributes}>{content}</h{level}>"))
+                }
+                HTMLTag::Link(url) => {
+                    let url = html_escape(&url);
+                    (None, format!("<a href=\"{url}\"{attributes}>{content}</a>"))
+                }
+                HTMLTag::BlockQuote(None) => {
+                    (Some((format!("blockquote{attributes}"), "")), content)
+                }
+                HTMLTag::BlockQuote(Some(source)) => {
+                    let (cite, source) = match &source.tag {
+                        HTMLTag::Link(url) => (
+                            format!(" cite=\"{}\"", html_escape(url)),
+                            self.render(vec![Value::Text(*source)]),
+                        ),
+                        _ => (String::new(), inline_html(&source)),
+                    };
+                    let html = format!("{content}<footer><cite>{source}</cite></footer>");
+                    (Some((format!("blockquote{cite}{attributes}"), "")), html)
+                }
+                HTMLTag::CodeBlock(language) => {
+                    let class = if language.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" class=\"language-{}\"", html_escape(&language))
+                    };
+                    let code = text.content.replace(LINE_BREAK, "\n");
+                    let code = match highlight(&code, &language) {
+                        Some(code) => {
+                            self.highlighted = true;
+                            code
+                        }
+                        None => html_escape(&code),
+                    };
+                    let html = format!("<pre{attributes}><code{class}>{code}</code></pre>");
+                    (None, html)
+                }
+                HTMLTag::Rule => (None, format!("<hr{attributes}>")),
+                HTMLTag::RawHtml => (None, text.content.replace(LINE_BREAK, "\n")),
+                HTMLTag::Diagram => {
+                    let source = html_escape(&text.content.replace(LINE_BREAK, "\n"));
+                    (None, format!("<pre{attributes}>{source}</pre>"))
+                }
+                HTMLTag::Math => (None, format!("<div{attributes}>{content}</div>")),
+                HTMLTag::Group { element, children } => {
+                    let html = format!(
+                        "<{element}{attributes}>\n{}\n</{element}>",
+                        self.render(children)
+                    );
+                    (None, html)
+                }
+                HTMLTag::Break => (None, format!("<br{attributes}>")),
+                HTMLTag::SlideBreak => continue,
+                HTMLTag::Toc => (
+                    None,
+                    format!("<nav class=\"toc\"{attributes}>\n{}\n</nav>", self.toc),
+                ),
+                HTMLTag::Bibliography => {
+                    let list = &self.bibliography;
+                    let html = format!("<ol class=\"bibliography\"{attributes}>\n{list}\n</ol>");
+                    (None, html)
+                }
+                HTMLTag::Image(url) => {
+                    let (url, alt) = (html_escape(&url), html_escape(&text.content));
+                    (
+                        None,
+                        format!("<img src=\"{url}\" alt=\"{alt}\"{attributes}>"),
+                    )
+                }
+                HTMLTag::Audio(url) => {
+                    let url = html_escape(&url);
+                    let html =
+                        format!("<audio controls src=\"{url}\"{attributes}>{content}</audio>");
+                    (None, html)
+                }
+                HTMLTag::Video { url, poster } => {
+                    let url = html_escape(&url);
+                    let poster = match poster {
+                        Some(poster) => format!(" poster=\"{}\"", html_escape(&poster)),
+                        None => String::new(),
+                    };
+                    let html = format!(
+                        "<video controls src=\"{url}\"{poster}{attributes}>{content}</video>"
+                    );
+                    (None, html)
+                }
+                HTMLTag::Embed(url) => (
+                    None,
+                    format!(
+                        "<iframe src=\"{}\" sandbox=\"allow-scripts allow-same-origin\"{attributes}>{content}</iframe>",
+                        html_escape(&url)
+                    ),
+                ),
+                HTMLTag::Details(summary, children) => {
+                    let html = format!(
+                        "<details{attributes}>\n<summary>{}</summary>\n{}\n</details>",
+                        inline_html(&summary),
+                        self.render(children)
+                    );
+                    (None, html)
+                }
+                HTMLTag::Figure(image, caption) => {
+                    let html = format!(
+                        "<figure{attributes}>{}<figcaption>{}</figcaption></figure>",
+                        self.render(vec![Value::Text(*image)]),
+                        inline_html(&caption)
+                    );
+                    (None, html)
+                }
+                HTMLTag::List => (
+                    Some(("ul".to_owned(), "</li>")),
+                    format!("<li{attributes}>{content}"),
+                ),
+                HTMLTag::OrderedList(start) => {
+                    let wrapper = match start {
+                        Some(start) => format!("ol start=\"{start}\""),
+                        None => "ol".to_owned(),
+                    };
+                    (
+                        Some((wrapper, "</li>")),
+                        format!("<li{attributes}>{content}"),
+                    )
+                }
+                HTMLTag::Task(done) => {
+                    let checked = if done { " checked" } else { "" };
+                    let html = format!(
+                        "<li{attributes}><input type=\"checkbox\"{checked} disabled> {content}"
+                    );
+                    (Some(("ul".to_owned(), "</li>")), html)
+                }
+                HTMLTag::Definition(term) => {
+                    let html =
+                        format!("<dt>{}</dt>\n<dd{attributes}>{content}", inline_html(&term));
+                    (Some(("dl".to_owned(), "</dd>")), html)
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let cell = if header { "th" } else { "td" };
+                    let cells: Vec<String> = (cells.iter())
+                        .map(|text| format!("<{cell}>{}</{cell}>", inline_html(text)))
+                        .collect();
+                    let html = format!("<tr{attributes}>{}</tr>", cells.concat());
+                    (Some(("table".to_owned(), "")), html)
+                }
+            };
+            // Nested items continue any list, and a wrapper with attributes
+            // like a list start number always opens a new group
+            let quote = |wrapper: &str| element(wrapper) == "blockquote";
+            let continued = group.first().is_some_and(|first| match &wrapper {
+                // Quotes only nest in quotes, and each top-level one stands alone
+                Some((wrapper, _)) if quote(wrapper) || quote(&first.wrapper) => {
+                    depth > 0 && quote(wrapper) && quote(&first.wrapper)
+                }
+                Some((_, close)) if depth > 0 => *close == first.close,
+                Some((wrapper, _)) => wrapper == element(&first.wrapper),
+                None => false,
+            });
+            if !continued && !group.is_empty() {
+                output.push(wrap_group(std::mem::take(&mut group)));
+            }
+            if let Some((wrapper, _)) = &wrapper
+                && quote(wrapper)
+            {
+                // Unlike list items, a quote can directly hold a deeper one
+                let level = group.last().map_or(0, |last| last.depth + 1);
+                for depth in level..depth {
+                    group.push(GroupItem {
+                        wrapper: "blockquote".to_owned(),
+                        depth,
+                        html: String::new(),
+                        close: "",
+                    });
+                }
+            }
+            match wrapper {
+                Some((wrapper, close)) => group.push(GroupItem {
+                    wrapper,
+                    depth,
+                    html,
+                    close,
+                }),
+                None => output.push(html),
+            }
+        }
+        if !group.is_empty() {
+            output.push(wrap_group(group));
+        }
+        output.join("\n")
+    }
+}
+
+/// Render the content of the text with its inline elements
+fn inline_html(text: &Text) -> String {
+    let mut content = html_escape(&text.content).replace(LINE_BREAK, "<br>");
+    content.extend(text.spans.iter().map(span_html));
+    (text.inline.iter()).fold(content, |content, inline| {
+        let tag = inline.tag();
+        format!("<{tag}>{content}</{}>", element(&tag))
+    })
+}
+
+/// Render the appended text inside the content of another one
+fn span_html(text: &Text) -> String {
+    let content = inline_html(text);
+    let attributes = text.html_attributes();
+    match &text.tag {
+        HTMLTag::Link(url) => {
+            let url = html_escape(url);
+            format!("<a href=\"{url}\"{attributes}>{content}</a>")
+        }
+        _ if attributes.is_empty() => content,
+        _ => format!("<span{attributes}>{content}</span>"),
+    }
+}
+
+/// Call the function on every text in document order, including the nested ones
+fn visit_texts(values: &mut [Value], f: &mut impl FnMut(&mut Text)) {
+    for value in values {
+        let Value::Text(text) = value else {
+            continue;
+        };
+        f(text);
+        if let HTMLTag::Group { children, .. } | HTMLTag::Details(_, children) = &mut text.tag {
+            visit_texts(children, f);
+        }
+    }
+}
+
+/// Give every heading an id, unique among the ones set by `id`, and return them
+fn heading_ids(data: &mut [Value]) -> Vec<(i32, Text)> {
+    let mut ids = HashSet::new();
+    visit_texts(data, &mut |text| {
+        ids.extend(text.attributes.id.clone());
+    });
+    let mut headings = Vec::new();
+    visit_texts(data, &mut |text| {
+        let HTMLTag::Heading(level) = text.tag else {
+            return;
+        };
+        if text.attributes.id.is_none() {
+            let slug = slugify(&text.plain());
+            let mut id = slug.clone();
+            let mut count = 0;
+            while ids.contains(&id) {
+                count += 1;
+                id = format!("{slug}-{count}");
+            }
+            ids.insert(id.clone());
+            text.attributes.id = Some(id);
+        }
+        headings.push((level, text.clone()));
+    });
+    headings
+}
+
+/// Turn the text into an id like `getting-started`
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if (c.is_whitespace() || c == '-' || c == '_' || c == LINE_BREAK)
+            && !slug.is_empty()
+            && !slug.ends_with('-')
+        {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "heading".to_owned()
+    } else {
+        slug.to_owned()
+    }
+}
+
+struct GroupItem {
+    wrapper: String,
+    depth: usize,
+    html: String,
+    close: &'static str,
+}
+
+/// Wrap the items, nesting deeper ones inside the item before them
+fn wrap_group(items: Vec<GroupItem>) -> String {
+    let mut html = String::new();
+    let mut open: Vec<(String, &str)> = Vec::new();
+    for item in items {
+        let depth = item.depth.min(open.len());
+        while open.len() > depth + 1 {
+            let (wrapper, close) = open.pop().unwrap_or_default();
+            html += &format!("{close}\n</{}>\n", element(&wrapper));
+        }
+        if let Some((_, close)) = open.get(depth) {
+            // Close the previous sibling
+            html += close;
+            html += "\n";
+        } else if !open.is_empty() {
+            html += "\n";
+        }
+        if open.len() == depth {
+            html += &format!("<{}>\n", item.wrapper);
+            open.push((item.wrapper, item.close));
+        }
+        html += &item.html;
+    }
+    while let Some((wrapper, close)) = open.pop() {
+        html += &format!("{close}\n</{}>", element(&wrapper));
+        if !open.is_empty() {
+            html += "\n";
+        }
+    }
+    html
+}
+
+/// Element name of the opening tag, without its attributes
+fn element(tag: &str) -> &str {
+    tag.split(' ').next().unwrap_or(tag)
+}
+
+/// Where the KaTeX assets for `math` are loaded from
+const KATEX: &str = "https://cdn.jsdelivr.net/npm/katex@0.16.11/dist";
+
+/// Where Mermaid for `diagram` is loaded from
+const MERMAID: &str = "https://cdn.jsdelivr.net/npm/mermaid@11/dist/mermaid.esm.min.mjs";
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+
+/// Class prefix of the highlighted spans, so they don't collide with the theme
+const HIGHLIGHT_CLASS: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Highlight the code as HTML spans, or `None` if the language isn't known
+fn highlight(code: &str, language: &str) -> Option<String> {
+    if language.is_empty() {
+        return None;
+    }
+    let syntax = SYNTAXES.find_syntax_by_token(language)?;
+    let mut generator =
+        ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, HIGHLIGHT_CLASS);
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+    Some(generator.finalize())
+}
+
+fn highlight_css() -> String {
+    let themes = ThemeSet::load_defaults();
+    let theme = &themes.themes["InspiredGitHub"];
+    css_for_theme_with_class_style(theme, HIGHLIGHT_CLASS).unwrap_or_default()
+}
+
+fn html_escape(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        match c {
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Render the document as CommonMark with the GitHub extensions
+fn markdown(stack: Stack) -> String {
+    let mut markdown = Markdown::default();
+    let mut data = stack.data;
+    let headings = heading_ids(&mut data);
+    let min = headings.iter().map(|(level, _)| *level).min().unwrap_or(1);
+    let toc = (headings.iter()).map(|(level, text)| {
+        let indent = "    ".repeat((level - min) as usize);
+        let id = text.attributes.id.clone().unwrap_or_default();
+        format!("{indent}- [{}](#{id})", inline_markdown(text))
+    });
+    markdown.toc = toc.collect::<Vec<_>>().join("\n");
+    let references = (stack.references.iter().enumerate()).map(|(index, reference)| {
+        let number = index + 1;
+        format!(
+            "{number}. <a id=\"ref-{number}\"></a>{}",
+            span_markdown(reference)
+        )
+    });
+    markdown.bibliography = references.collect::<Vec<_>>().join("\n");
+    let mut body = markdown.render(data);
+    for (index, note) in markdown.footnotes.iter().enumerate() {
+        body += &format!("\n\n[^{}]: {}", index + 1, inline_markdown(note));
+    }
+    body + "\n"
+}
+
+/// State shared while rendering Markdown, like `Html`
+#[derive(Default)]
+struct Markdown {
+    footnotes: Vec<Text>,
+    toc: String,
+    bibliography: String,
+}
+
+/// Kind of a Markdown block which continues on the next line, unlike paragraphs
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    List,
+    Table,
+    Quote,
+}
+
+impl Markdown {
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output = String::new();
+        let mut previous = None;
+        for value in values {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut content = inline_markdown(&text);
+            for note in text.footnotes {
+                self.footnotes.push(note);
+                content += &format!("[^{}]", self.footnotes.len());
+            }
+            let depth = text.depth;
+            let indent = "    ".repeat(depth);
+
+            let (block, markdown) = match text.tag {
+                HTMLTag::Paragraph => (None, content),
+                HTMLTag::Math => (
+                    None,
+                    format!("$$\n{}\n$$", text.content.replace(LINE_BREAK, "\n")),
+                ),
+                HTMLTag::Heading(level) => {
+                    let level = level.clamp(1, 6) as usize;
+                    (None, format!("{} {content}", "#".repeat(level)))
+                }
+                HTMLTag::Link(url) => (None, format!("[{content}]({})", markdown_url(&url))),
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = content;
+                    if let Some(source) = source {
+                        quote += &format!("\n\u{2014} {}", span_markdown(&source));
+                    }
+                    let prefix = "> ".repeat(depth + 1);
+                    let lines: Vec<String> = (quote.lines())
+                        .map(|line| format!("{prefix}{line}").trim_end().to_owned())
+                        .collect();
+                    (Some(Block::Quote), lines.join("\n"))
+                }
+                HTMLTag::CodeBlock(language) => (
+                    None,
+                    fence(&text.content.replace(LINE_BREAK, "\n"), &language),
+                ),
+                HTMLTag::Diagram => (
+                    None,
+                    fence(&text.content.replace(LINE_BREAK, "\n"), "mermaid"),
+                ),
+                HTMLTag::Rule => (None, "---".to_owned()),
+                HTMLTag::Break => (None, "<br>".to_owned()),
+                HTMLTag::SlideBreak => continue,
+                HTMLTag::RawHtml => (None, text.content.replace(LINE_BREAK, "\n")),
+                HTMLTag::Toc => (None, self.toc.clone()),
+                HTMLTag::Bibliography => (None, self.bibliography.clone()),
+                HTMLTag::Group { children, .. } => (None, self.render(children)),
+                HTMLTag::Details(summary, children) => {
+                    let html = format!(
+                        "<details>\n<summary>{}</summary>\n\n{}\n\n</details>",
+                        inline_html(&summary),
+                        self.render(children)
+                    );
+                    (None, html)
+                }
+                HTMLTag::Image(url) => (None, format!("![{content}]({})", markdown_url(&url))),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    let alt = inline_markdown(&image);
+                    let caption = inline_markdown(&caption);
+                    (
+                        None,
+                        format!("![{alt}]({})\n*{caption}*", markdown_url(url)),
+                    )
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    let content = if content.is_empty() {
+                        markdown_escape(&url)
+                    } else {
+                        content
+                    };
+                    (None, format!("[{content}]({})", markdown_url(&url)))
+                }
+                HTMLTag::List => (Some(Block::List), format!("{indent}- {content}")),
+                HTMLTag::OrderedList(start) => {
+                    let mut item = format!("{indent}{}. {content}", start.unwrap_or(1));
+                    // Only an HTML comment keeps adjacent lists apart
+                    if start.is_some() && depth == 0 && previous == Some(Block::List) {
+                        item.insert_str(0, "<!-- -->\n\n");
+                        previous = None;
+                    }
+                    (Some(Block::List), item)
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { 'x' } else { ' ' };
+                    (Some(Block::List), format!("{indent}- [{mark}] {content}"))
+                }
+                HTMLTag::Definition(term) => {
+                    let term = inline_markdown(&term);
+                    (None, format!("**{term}**\\\n{content}"))
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let cells: Vec<String> = cells.iter().map(inline_markdown).collect();
+                    let row = format!("| {} |", cells.join(" | "));
+                    let rule = format!("|{}", " --- |".repeat(cells.len()));
+                    let row = match (header, previous == Some(Block::Table)) {
+                        (true, false) => format!("{row}\n{rule}"),
+                        (false, false) => {
+                            format!("|{}\n{rule}\n{row}", "  |".repeat(cells.len()))
+                        }
+                        _ => row,
+                    };
+                    (Some(Block::Table), row)
+                }
+            };
+            if !output.is_empty() {
+                let separator = match block {
+                    Some(Block::Quote) if depth > 0 && previous == block => {
+                        format!("\n{}\n", "> ".repeat(depth).trim_end())
+                    }
+                    Some(Block::List | Block::Table) if previous == block => "\n".to_owned(),
+                    _ => "\n\n".to_owned(),
+                };
+                output += &separator;
+            }
+            output += &markdown;
+            previous = block;
+        }
+        output
+    }
+}
+
+/// Render the content of the text with its inline elements as Markdown
+fn inline_markdown(text: &Text) -> String {
+    let code = matches!(text.inline.first(), Some(Inline::Code));
+    let mut content = if code {
+        text.content.replace(LINE_BREAK, " ")
+    } else {
+        markdown_escape(&text.content).replace(LINE_BREAK, "<br>")
+    };
+    content.extend(text.spans.iter().map(span_markdown));
+    (text.inline.iter()).fold(content, |content, inline| match inline {
+        Inline::Bold => format!("**{content}**"),
+        Inline::Italic => format!("*{content}*"),
+        Inline::Strike => format!("~~{content}~~"),
+        Inline::Code => {
+            let ticks =
+                "`".repeat(content.split(|c| c != '`').map(str::len).max().unwrap_or(0) + 1);
+            format!("{ticks}{content}{ticks}")
+        }
+        Inline::Math => format!("${content}$"),
+        _ => {
+            let tag = inline.tag();
+            format!("<{tag}>{content}</{}>", element(&tag))
+        }
+    })
+}
+
+/// Render the appended text inside the content of another one as Markdown
+fn span_markdown(text: &Text) -> String {
+    let content = inline_markdown(text);
+    match &text.tag {
+        HTMLTag::Link(url) => format!("[{content}]({})", markdown_url(url)),
+        _ => content,
+    }
+}
+
+/// Link destination, in angle brackets if a space or a parenthesis would end it early
+fn markdown_url(url: &str) -> String {
+    if !url.contains([' ', '(', ')', '<', '>']) {
+        return url.to_owned();
+    }
+    format!("<{}>", url.replace('<', "\\<").replace('>', "\\>"))
+}
+
+/// Fenced code block, whose fence is longer than any backtick run in the code
+fn fence(code: &str, language: &str) -> String {
+    let longest = code.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{language}\n{code}\n{fence}")
+}
+
+/// Escape the characters which would be taken as Markdown syntax
+fn markdown_escape(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        if "\\`*_[]<>#|~".contains(c) || (result.is_empty() && "-+=".contains(c)) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Render the document as a LaTeX article
+fn latex(stack: Stack) -> String {
+    let mut data = stack.data;
+    heading_ids(&mut data);
+    let latex = Latex {
+        references: stack.references,
+    };
+    let body = latex.render(data);
+
+    let mut preamble = vec![
+        "\\documentclass{article}".to_owned(),
+        "\\usepackage{amsmath, amssymb, graphicx, hyperref}".to_owned(),
+        "\\usepackage[normalem]{ulem}".to_owned(),
+    ];
+    if let Some(title) = &stack.title {
+        preamble.push(format!("\\title{{{}}}", latex_escape(title)));
+        let author = (stack.meta.iter()).find(|(name, _)| name == "author");
+        let author = author.map(|(_, author)| latex_escape(author));
+        preamble.push(format!("\\author{{{}}}", author.unwrap_or_default()));
+        preamble.push("\\date{}".to_owned());
+    }
+    let title = match stack.title {
+        Some(_) => "\\maketitle\n\n",
+        None => "",
+    };
+    format!(
+        "{}\n\n\\begin{{document}}\n\n{title}{body}\n\n\\end{{document}}\n",
+        preamble.join("\n")
+    )
+}
+
+/// State shared while rendering LaTeX, like `Html`
+struct Latex {
+    references: Vec<Text>,
+}
+
+impl Latex {
+    fn render(&self, values: Vec<Value>) -> String {
+        let mut output: Vec<String> = Vec::new();
+        // Environments of the open lists from the outermost, and rows of the table
+        let mut lists: Vec<&str> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        for value in values {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut content = inline_latex(&text);
+            for note in &text.footnotes {
+                content += &format!("\\footnote{{{}}}", inline_latex(note));
+            }
+
+            if !matches!(text.tag, HTMLTag::TableRow { .. }) && !rows.is_empty() {
+                output.push(tabular(std::mem::take(&mut rows)));
+            }
+            let list = match text.tag {
+                HTMLTag::List | HTMLTag::Task(_) => Some("itemize"),
+                HTMLTag::OrderedList(_) => Some("enumerate"),
+                HTMLTag::Definition(_) => Some("description"),
+                _ => None,
+            };
+            // Like in HTML, a start number always begins a new list
+            let restart = matches!(text.tag, HTMLTag::OrderedList(Some(_)));
+            let depth = match list {
+                Some(_) => text.depth.min(lists.len()),
+                None => 0,
+            };
+            while lists.len() > depth + usize::from(list.is_some())
+                || lists.len() == depth + 1 && (restart || lists.last().copied() != list)
+            {
+                let list = lists.pop().unwrap_or_default();
+                output.push(format!("\\end{{{list}}}"));
+            }
+            if let Some(list) = list
+                && lists.len() == depth
+            {
+                output.push(format!("\\begin{{{list}}}"));
+                lists.push(list);
+            }
+
+            let latex = match text.tag {
+                HTMLTag::Paragraph => content,
+                HTMLTag::Heading(level) => {
+                    let command = match level {
+                        ..=1 => "section",
+                        2 => "subsection",
+                        3 => "subsubsection",
+                        4 => "paragraph",
+                        _ => "subparagraph",
+                    };
+                    let id = text.attributes.id.clone().unwrap_or_default();
+                    format!("\\{command}{{{content}}}\\label{{{}}}", latex_escape(&id))
+                }
+                HTMLTag::Link(url) => link_latex(&url, &content),
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = content;
+                    if let Some(source) = source {
+                        quote += &format!("\\\\\n--- {}", span_latex(&source));
+                    }
+                    for _ in 0..=text.depth {
+                        quote = format!("\\begin{{quote}}\n{quote}\n\\end{{quote}}");
+                    }
+                    quote
+                }
+                HTMLTag::CodeBlock(_) | HTMLTag::Diagram => {
+                    let code = text.content.replace(LINE_BREAK, "\n");
+                    format!("\\begin{{verbatim}}\n{code}\n\\end{{verbatim}}")
+                }
+                HTMLTag::Math => format!("\\[\n{}\n\\]", text.content.replace(LINE_BREAK, "\n")),
+                HTMLTag::Rule => "\\noindent\\rule{\\linewidth}{0.4pt}".to_owned(),
+                HTMLTag::Break => "\\medskip".to_owned(),
+                HTMLTag::SlideBreak => continue,
+                HTMLTag::RawHtml => continue,
+                HTMLTag::Toc => "\\tableofcontents".to_owned(),
+                HTMLTag::Bibliography => {
+                    let items: Vec<String> = (self.references.iter().enumerate())
+                        .map(|(index, reference)| {
+                            format!("\\bibitem{{ref-{}}} {}", index + 1, span_latex(reference))
+                        })
+                        .collect();
+                    format!(
+                        "\\begin{{thebibliography}}{{99}}\n{}\n\\end{{thebibliography}}",
+                        items.join("\n")
+                    )
+                }
+                HTMLTag::Group { children, .. } => self.render(children),
+                HTMLTag::Details(summary, children) => {
+                    let summary = inline_latex(&summary);
+                    format!("\\paragraph{{{summary}}}\n\n{}", self.render(children))
+                }
+                HTMLTag::Image(url) => image_latex(&url, &content),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    format!(
+                        "\\begin{{figure}}[h]\n\\centering\n{}\n\\caption{{{}}}\n\\end{{figure}}",
+                        image_latex(url, &inline_latex(&image)),
+                        inline_latex(&caption)
+                    )
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    let content = if content.is_empty() {
+                        format!("\\url{{{}}}", latex_url(&url))
+                    } else {
+                        content
+                    };
+                    link_latex(&url, &content)
+                }
+                HTMLTag::List => format!("\\item {content}"),
+                HTMLTag::OrderedList(start) => {
+                    let mut item = format!("\\item {content}");
+                    if let Some(start) = start {
+                        let counter = ["i", "ii", "iii", "iv"][depth.min(3)];
+                        item.insert_str(
+                            0,
+                            &format!("\\setcounter{{enum{counter}}}{{{}}}\n", start - 1),
+                        );
+                    }
+                    item
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { "$\\boxtimes$" } else { "$\\square$" };
+                    format!("\\item[{mark}] {content}")
+                }
+                HTMLTag::Definition(term) => format!("\\item[{}] {content}", inline_latex(&term)),
+                HTMLTag::TableRow { cells, header } => {
+                    rows.push((header, cells.iter().map(inline_latex).collect()));
+                    continue;
+                }
+            };
+            output.push(latex);
+        }
+        if !rows.is_empty() {
+            output.push(tabular(rows));
+        }
+        while let Some(list) = lists.pop() {
+            output.push(format!("\\end{{{list}}}"));
+        }
+        output.join("\n\n")
+    }
+}
+
+/// Table of the rows, with a rule under the header ones
+fn tabular(rows: Vec<(bool, Vec<String>)>) -> String {
+    let columns = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(0);
+    let mut latex = format!("\\begin{{tabular}}{{{}}}\n\\hline", "l".repeat(columns));
+    for (header, cells) in rows {
+        let cells: Vec<String> = match header {
+            true => cells
+                .iter()
+                .map(|cell| format!("\\textbf{{{cell}}}"))
+                .collect(),
+            false => cells,
+        };
+        latex += &format!("\n{} \\\\", cells.join(" & "));
+        if header {
+            latex += " \\hline";
+        }
+    }
+    latex + "\n\\hline\n\\end{tabular}"
+}
+
+/// Render the content of the text with its inline elements as LaTeX
+fn inline_latex(text: &Text) -> String {
+    let math = matches!(text.inline.first(), Some(Inline::Math));
+    let mut content = if math {
+        text.content.clone()
+    } else {
+        latex_escape(&text.content).replace(LINE_BREAK, "\\\\\n")
+    };
+    content.extend(text.spans.iter().map(span_latex));
+    (text.inline.iter()).fold(content, |content, inline| match inline {
+        Inline::Bold => format!("\\textbf{{{content}}}"),
+        Inline::Italic => format!("\\emph{{{content}}}"),
+        Inline::Strike => format!("\\sout{{{content}}}"),
+        Inline::Code | Inline::Kbd => format!("\\texttt{{{content}}}"),
+        Inline::Sup => format!("\\textsuperscript{{{content}}}"),
+        Inline::Sub => format!("\\textsubscript{{{content}}}"),
+        Inline::Mark => format!("\\uline{{{content}}}"),
+        Inline::Small => format!("{{\\small {content}}}"),
+        Inline::Math => format!("${content}$"),
+        Inline::Abbr(_) => content,
+    })
+}
+
+/// Render the appended text inside the content of another one as LaTeX
+fn span_latex(text: &Text) -> String {
+    match &text.tag {
+        // Citations made by `cite`
+        HTMLTag::Link(url) if url.starts_with("#ref-") => format!("\\cite{{{}}}", &url[1..]),
+        HTMLTag::Link(url) => link_latex(url, &inline_latex(text)),
+        _ => inline_latex(text),
+    }
+}
+
+/// Hyperlink to the address or to the label of the anchor
+fn link_latex(url: &str, content: &str) -> String {
+    match url.strip_prefix('#') {
+        Some(label) => format!("\\hyperref[{}]{{{content}}}", latex_escape(label)),
+        None => format!("\\href{{{}}}{{{content}}}", latex_url(url)),
+    }
+}
+
+/// Image of the local file, or a link to a remote one, which LaTeX can't load
+fn image_latex(url: &str, alt: &str) -> String {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return match alt.is_empty() {
+            true => format!("\\url{{{}}}", latex_url(url)),
+            false => format!("\\href{{{}}}{{{alt}}}", latex_url(url)),
+        };
+    }
+    // Embedded images have nothing to load from either
+    if url.starts_with("data:") {
+        return alt.to_owned();
+    }
+    format!("\\includegraphics[width=\\linewidth]{{{}}}", latex_url(url))
+}
+
+/// Escape the characters which would break an address in `\href` or `\url`
+fn latex_url(url: &str) -> String {
+    url.replace('%', "\\%").replace('#', "\\#")
+}
+
+/// Escape the characters which are special in LaTeX
+fn latex_escape(text: &str) -> String {
+    let mut result = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\textbackslash{}"),
+            '~' => result.push_str("\\textasciitilde{}"),
+            '^' => result.push_str("\\textasciicircum{}"),
+            // The default font encoding has other glyphs in their place
+            '<' => result.push_str("\\textless{}"),
+            '>' => result.push_str("\\textgreater{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                result.push('\\');
+                result.push(c);
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Package the document as an EPUB book, with a chapter from each top-level heading
+fn epub(stack: Stack) -> Result<Vec<u8>, Error> {
+    let mut data = stack.data;
+    let headings = heading_ids(&mut data);
+    let top = (data.iter())
+        .filter_map(|value| match value {
+            Value::Text(text) => match text.tag {
+                HTMLTag::Heading(level) => Some(level),
+                _ => None,
+            },
+            _ => None,
+        })
+        .min();
+    let mut chapters: Vec<Vec<Value>> = Vec::new();
+    for value in data {
+        let top_level = match &value {
+            Value::Text(text) => matches!(text.tag, HTMLTag::Heading(level) if Some(level) == top),
+            _ => false,
+        };
+        match chapters.last_mut() {
+            Some(chapter) if !top_level => chapter.push(value),
+            _ => chapters.push(vec![value]),
+        }
+    }
+    if chapters.is_empty() {
+        chapters.push(Vec::new());
+    }
+
+    // Headings link to the chapter they're in
+    let mut files = HashMap::new();
+    for (index, chapter) in chapters.iter_mut().enumerate() {
+        visit_texts(chapter, &mut |text| {
+            if let (HTMLTag::Heading(_), Some(id)) = (&text.tag, &text.attributes.id) {
+                files.insert(id.clone(), format!("chapter-{}.xhtml", index + 1));
+            }
+        });
+    }
+    let toc = |list: &str, prefix: bool| {
+        let items = (headings.iter()).map(|(level, text)| {
+            let id = text.attributes.id.clone().unwrap_or_default();
+            let file = files.get(&id).cloned().unwrap_or_default();
+            let href = if prefix { format!("{file}#{id}") } else { file };
+            (*level, inline_html(text), href)
+        });
+        toc_html(items.collect(), list)
+    };
+
+    let title = html_escape(stack.title.as_deref().unwrap_or("Untitled"));
+    let lang = html_escape(stack.lang.as_deref().unwrap_or("en"));
+    let document = |body: &str, head: &str| {
+        let html = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" lang=\"{lang}\" xml:lang=\"{lang}\">\n<head>\n<meta charset=\"UTF-8\">\n<title>{title}</title>{head}\n</head>\n<body>\n{body}\n</body>\n</html>\n"
+        );
+        xhtml(&pretty(&html))
+    };
+
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut manifest = vec![
+        "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>"
+            .to_owned(),
+    ];
+    let mut spine = Vec::new();
+    for (index, chapter) in chapters.into_iter().enumerate() {
+        let mut html = Html {
+            toc: toc("ul", true),
+            bibliography: bibliography_html(&stack.references),
+            ..Html::default()
+        };
+        let body = html.render(chapter) + &html.footnotes_html();
+        let head = match html.highlighted {
+            true => format!("\n<style>{}</style>", highlight_css()),
+            false => String::new(),
+        };
+        let name = format!("chapter-{}", index + 1);
+        let remote = match body.contains("src=\"http") {
+            true => " properties=\"remote-resources\"",
+            false => "",
+        };
+        manifest.push(format!(
+            "<item id=\"{name}\" href=\"{name}.xhtml\" media-type=\"application/xhtml+xml\"{remote}/>"
+        ));
+        spine.push(format!("<itemref idref=\"{name}\"/>"));
+        files.push((format!("{name}.xhtml"), document(&body, &head).into_bytes()));
+    }
+    let contents = match headings.is_empty() {
+        true => format!("<ol>\n<li><a href=\"chapter-1.xhtml\">{title}</a></li>\n</ol>"),
+        false => toc("ol", false),
+    };
+    let nav = format!("<nav epub:type=\"toc\" id=\"toc\">\n<h1>{title}</h1>\n{contents}\n</nav>");
+    files.push(("nav.xhtml".to_owned(), document(&nav, "").into_bytes()));
+    for (index, (path, name)) in stack.assets.iter().enumerate() {
+        let bytes = std::fs::read(path).map_err(|e| ErrorKind::Io(path.clone(), e))?;
+        let media = image_type(path).unwrap_or("application/octet-stream");
+        let name = html_escape(name);
+        manifest.push(format!(
+            "<item id=\"asset-{}\" href=\"{name}\" media-type=\"{media}\"/>",
+            index + 1
+        ));
+        files.push((name, bytes));
+    }
+
+    let mut metadata = vec![
+        format!(
+            "<dc:identifier id=\"id\">{}</dc:identifier>",
+            book_id(&stack.canonical, &files)
+        ),
+        format!("<dc:title>{title}</dc:title>"),
+        format!("<dc:language>{lang}</dc:language>"),
+    ];
+    for (name, content) in &stack.meta {
+        let element = match name.as_str() {
+            "author" => "creator",
+            "description" => "description",
+            _ => continue,
+        };
+        metadata.push(format!(
+            "<dc:{element}>{}</dc:{element}>",
+            html_escape(content)
+        ));
+    }
+    let modified = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+    metadata.push(format!(
+        "<meta property=\"dcterms:modified\">{modified}</meta>"
+    ));
+    let package = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\">\n<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}\n</metadata>\n<manifest>\n{}\n</manifest>\n<spine>\n{}\n</spine>\n</package>\n",
+        metadata.join("\n"),
+        manifest.join("\n"),
+        spine.join("\n")
+    );
+    let container = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n<rootfiles>\n<rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\"/>\n</rootfiles>\n</container>\n";
+
+    // The mimetype has to come first for readers to recognize the book
+    let entries = [
+        ("mimetype".to_owned(), b"application/epub+zip".to_vec()),
+        (
+            "META-INF/container.xml".to_owned(),
+            container.as_bytes().to_vec(),
+        ),
+        ("OEBPS/content.opf".to_owned(), package.into_bytes()),
+    ];
+    let files = files
+        .into_iter()
+        .map(|(name, bytes)| (format!("OEBPS/{name}"), bytes));
+    archive(entries.into_iter().chain(files))
+}
+
+/// Zip archive of the files, leaving a `mimetype` file uncompressed as the containers require
+fn archive(files: impl IntoIterator<Item = (String, Vec<u8>)>) -> Result<Vec<u8>, Error> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, bytes) in files {
+        let options = if name == "mimetype" { stored } else { deflated };
+        zip.start_file(name, options).map_err(ErrorKind::Package)?;
+        zip.write_all(&bytes)
+            .map_err(|e| ErrorKind::Package(e.into()))?;
+    }
+    let archive = zip.finish().map_err(ErrorKind::Package)?;
+    Ok(archive.into_inner())
+}
+
+/// Package the document as a Word file in the Office Open XML format
+fn docx(stack: Stack) -> Result<Vec<u8>, Error> {
+    let mut data = stack.data;
+    let headings = heading_ids(&mut data);
+    let mut docx = Docx {
+        relationships: vec![
+            ("styles", "styles.xml".to_owned(), false),
+            ("numbering", "numbering.xml".to_owned(), false),
+            ("footnotes", "footnotes.xml".to_owned(), false),
+        ],
+        assets: stack.assets,
+        references: stack.references,
+        ..Docx::default()
+    };
+    let min = headings.iter().map(|(level, _)| *level).min().unwrap_or(1);
+    for (level, text) in &headings {
+        let id = text.attributes.id.clone().unwrap_or_default();
+        let runs = docx.runs(text, &RunStyle::default());
+        let indent = format!("<w:ind w:left=\"{}\"/>", 360 * (level - min));
+        let link = format!(
+            "<w:hyperlink w:anchor=\"{}\">{runs}</w:hyperlink>",
+            bookmark_name(&id)
+        );
+        docx.toc.push(docx_paragraph(None, &indent, &link));
+    }
+    let mut body = docx.render(data)?;
+    // The body can't end with a table
+    if body.ends_with("</w:tbl>") {
+        body += "\n<w:p/>";
+    }
+
+    let namespaces = "xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\" xmlns:r=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships\"";
+    let document = format!(
+        "{XML_DECLARATION}\n<w:document {namespaces} xmlns:wp=\"http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing\">\n<w:body>\n{body}\n<w:sectPr><w:pgSz w:w=\"11906\" w:h=\"16838\"/><w:pgMar w:top=\"1440\" w:right=\"1440\" w:bottom=\"1440\" w:left=\"1440\" w:header=\"720\" w:footer=\"720\" w:gutter=\"0\"/></w:sectPr>\n</w:body>\n</w:document>\n"
+    );
+    let notes = (docx.footnotes.iter().enumerate())
+        .map(|(index, note)| format!("<w:footnote w:id=\"{}\">{note}</w:footnote>", index + 1));
+    let footnotes = format!(
+        "{XML_DECLARATION}\n<w:footnotes {namespaces}>\n<w:footnote w:type=\"separator\" w:id=\"-1\"><w:p><w:r><w:separator/></w:r></w:p></w:footnote>\n<w:footnote w:type=\"continuationSeparator\" w:id=\"0\"><w:p><w:r><w:continuationSeparator/></w:r></w:p></w:footnote>\n{}\n</w:footnotes>\n",
+        notes.collect::<Vec<_>>().join("\n")
+    );
+    let relationships = (docx.relationships.iter().enumerate()).map(|(index, (kind, target, external))| {
+        let mode = if *external { " TargetMode=\"External\"" } else { "" };
+        format!(
+            "<Relationship Id=\"rId{}\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/{kind}\" Target=\"{}\"{mode}/>",
+            index + 1,
+            html_escape(target)
+        )
+    });
+    let relationships = format!(
+        "{XML_DECLARATION}\n<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">\n{}\n</Relationships>\n",
+        relationships.collect::<Vec<_>>().join("\n")
+    );
+    let lang = match &stack.lang {
+        Some(lang) => format!("<w:lang w:val=\"{}\"/>", html_escape(lang)),
+        None => String::new(),
+    };
+    let styles = format!(
+        "{XML_DECLARATION}\n<w:styles {namespaces}>\n<w:docDefaults><w:rPrDefault><w:rPr><w:sz w:val=\"22\"/>{lang}</w:rPr></w:rPrDefault><w:pPrDefault><w:pPr><w:spacing w:after=\"160\" w:line=\"276\" w:lineRule=\"auto\"/></w:pPr></w:pPrDefault></w:docDefaults>\n{}\n</w:styles>\n",
+        docx_styles().join("\n")
+    );
+    let numbering = docx_numbering(&docx.lists);
+
+    let mut properties = Vec::new();
+    if let Some(title) = &stack.title {
+        properties.push(format!("<dc:title>{}</dc:title>", html_escape(title)));
+    }
+    for (name, content) in &stack.meta {
+        let element = match name.as_str() {
+            "author" => "creator",
+            "description" => "description",
+            _ => continue,
+        };
+        properties.push(format!(
+            "<dc:{element}>{}</dc:{element}>",
+            html_escape(content)
+        ));
+    }
+    let core = format!(
+        "{XML_DECLARATION}\n<cp:coreProperties xmlns:cp=\"http://schemas.openxmlformats.org/package/2006/metadata/core-properties\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}\n</cp:coreProperties>\n",
+        properties.join("\n")
+    );
+    let content_types = format!(
+        "{XML_DECLARATION}\n<Types xmlns=\"http://schemas.openxmlformats.org/package/2006/content-types\">\n<Default Extension=\"rels\" ContentType=\"application/vnd.openxmlformats-package.relationships+xml\"/>\n<Default Extension=\"xml\" ContentType=\"application/xml\"/>\n{}\n<Override PartName=\"/word/document.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.wordprocessingml.document.main+xml\"/>\n<Override PartName=\"/word/styles.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.wordprocessingml.styles+xml\"/>\n<Override PartName=\"/word/numbering.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.wordprocessingml.numbering+xml\"/>\n<Override PartName=\"/word/footnotes.xml\" ContentType=\"application/vnd.openxmlformats-officedocument.wordprocessingml.footnotes+xml\"/>\n<Override PartName=\"/docProps/core.xml\" ContentType=\"application/vnd.openxmlformats-package.core-properties+xml\"/>\n</Types>\n",
+        (DOCX_IMAGES.iter())
+            .map(|(extension, mime)| format!(
+                "<Default Extension=\"{extension}\" ContentType=\"{mime}\"/>"
+            ))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    let package = format!(
+        "{XML_DECLARATION}\n<Relationships xmlns=\"http://schemas.openxmlformats.org/package/2006/relationships\">\n<Relationship Id=\"rId1\" Type=\"http://schemas.openxmlformats.org/officeDocument/2006/relationships/officeDocument\" Target=\"word/document.xml\"/>\n<Relationship Id=\"rId2\" Type=\"http://schemas.openxmlformats.org/package/2006/relationships/metadata/core-properties\" Target=\"docProps/core.xml\"/>\n</Relationships>\n"
+    );
+
+    let files = [
+        ("[Content_Types].xml", content_types),
+        ("_rels/.rels", package),
+        ("docProps/core.xml", core),
+        ("word/document.xml", document),
+        // Links in the footnotes refer to the same relationships
+        ("word/_rels/footnotes.xml.rels", relationships.clone()),
+        ("word/_rels/document.xml.rels", relationships),
+        ("word/styles.xml", styles),
+        ("word/numbering.xml", numbering),
+        ("word/footnotes.xml", footnotes),
+    ];
+    let files = files.map(|(name, xml)| (name.to_owned(), xml.into_bytes()));
+    let media = (docx.media.into_iter()).map(|(name, bytes)| (format!("word/media/{name}"), bytes));
+    archive(files.into_iter().chain(media))
+}
+
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>";
+
+/// Extensions and types of the images Word can show
+const DOCX_IMAGES: [(&str, &str); 4] = [
+    ("png", "image/png"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("bmp", "image/bmp"),
+];
+
+/// State shared while rendering Word XML, like `Html`
+#[derive(Default)]
+struct Docx {
+    /// Kind and target of the relationships of the document, whose IDs are `rId` and the index from 1
+    relationships: Vec<(&'static str, String, bool)>,
+    /// Images stored in `word/media`
+    media: Vec<(String, Vec<u8>)>,
+    /// Paragraphs of the footnotes, numbered from 1
+    footnotes: Vec<String>,
+    /// Level and start number of the ordered lists, each numbered on its own
+    lists: Vec<(usize, i32)>,
+    /// Number of the bookmarks and drawings made so far
+    count: usize,
+    assets: Vec<(PathBuf, String)>,
+    references: Vec<Text>,
+    toc: Vec<String>,
+}
+
+/// Formatting of a run of text
+#[derive(Clone, Default)]
+struct RunStyle {
+    link: bool,
+    code: bool,
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    small: bool,
+    mark: bool,
+    /// Vertical alignment like `superscript`
+    align: Option<&'static str>,
+}
+
+impl RunStyle {
+    /// Run properties in the order the schema requires
+    fn xml(&self) -> String {
+        let properties = [
+            (self.link, "<w:rStyle w:val=\"Hyperlink\"/>"),
+            (
+                self.code,
+                "<w:rFonts w:ascii=\"Courier New\" w:hAnsi=\"Courier New\" w:cs=\"Courier New\"/>",
+            ),
+            (self.bold, "<w:b/>"),
+            (self.italic, "<w:i/>"),
+            (self.strike, "<w:strike/>"),
+            (self.small, "<w:sz w:val=\"18\"/>"),
+            (self.mark, "<w:highlight w:val=\"yellow\"/>"),
+        ];
+        let mut xml: String = (properties.iter())
+            .filter(|(on, _)| *on)
+            .map(|(_, property)| *property)
+            .collect();
+        if let Some(align) = self.align {
+            xml += &format!("<w:vertAlign w:val=\"{align}\"/>");
+        }
+        match xml.is_empty() {
+            true => xml,
+            false => format!("<w:rPr>{xml}</w:rPr>"),
+        }
+    }
+}
+
+impl Docx {
+    fn render(&mut self, values: Vec<Value>) -> Result<String, Error> {
+        let mut output: Vec<String> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        // Numbering of the ordered list being continued
+        let mut list = None;
+        let mut values = values.into_iter().peekable();
+        while let Some(value) = values.next() {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut runs = self.runs(&text, &RunStyle::default());
+            for note in &text.footnotes {
+                // The note begins with its number
+                let number = "<w:r><w:rPr><w:vertAlign w:val=\"superscript\"/></w:rPr><w:footnoteRef/></w:r>";
+                let note = number.to_owned()
+                    + &docx_run(" ", &RunStyle::default())
+                    + &self.runs(note, &RunStyle::default());
+                self.footnotes.push(docx_paragraph(None, "", &note));
+                runs += &format!(
+                    "<w:r><w:rPr><w:vertAlign w:val=\"superscript\"/></w:rPr><w:footnoteReference w:id=\"{}\"/></w:r>",
+                    self.footnotes.len()
+                );
+            }
+            let depth = text.depth;
+            let indent = |level: usize| format!("<w:ind w:left=\"{}\"/>", 720 * level);
+            let listed = matches!(
+                text.tag,
+                HTMLTag::List | HTMLTag::OrderedList(_) | HTMLTag::Task(_)
+            );
+            if !listed {
+                list = None;
+            }
+
+            let xml = match text.tag {
+                HTMLTag::Paragraph => docx_paragraph(None, "", &runs),
+                HTMLTag::Heading(level) => {
+                    let style = format!("Heading{}", level.clamp(1, 6));
+                    let id = text.attributes.id.clone().unwrap_or_default();
+                    let runs = self.bookmark(&id, &runs);
+                    docx_paragraph(Some(&style), "", &runs)
+                }
+                HTMLTag::Link(url) => {
+                    let runs = match runs.is_empty() {
+                        true => docx_run(&url, &RunStyle::default()),
+                        false => runs,
+                    };
+                    let link = self.hyperlink(&url, &runs);
+                    docx_paragraph(None, "", &link)
+                }
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = docx_paragraph(Some("Quote"), &indent(depth + 1), &runs);
+                    if let Some(source) = source {
+                        let source = docx_run("\u{2014} ", &RunStyle::default())
+                            + &self.span(&source, &RunStyle::default());
+                        quote += &docx_paragraph(Some("Quote"), &indent(depth + 1), &source);
+                    }
+                    quote
+                }
+                HTMLTag::CodeBlock(_) | HTMLTag::Diagram | HTMLTag::Math => {
+                    // Triple-quoted code holds plain newlines rather than line breaks
+                    let code = text.content.replace('\n', &LINE_BREAK.to_string());
+                    let code = docx_run(&code, &RunStyle::default());
+                    docx_paragraph(Some("Code"), "", &code)
+                }
+                HTMLTag::Rule => docx_paragraph(
+                    None,
+                    "<w:pBdr><w:bottom w:val=\"single\" w:sz=\"6\" w:space=\"1\" w:color=\"auto\"/></w:pBdr>",
+                    "",
+                ),
+                HTMLTag::Break => docx_paragraph(None, "", ""),
+                HTMLTag::SlideBreak | HTMLTag::RawHtml => continue,
+                HTMLTag::Toc => self.toc.join(""),
+                HTMLTag::Bibliography => {
+                    let references = std::mem::take(&mut self.references);
+                    let mut items = String::new();
+                    for (index, reference) in references.iter().enumerate() {
+                        let number = index + 1;
+                        let mark = docx_run(&format!("[{number}] "), &RunStyle::default());
+                        let runs = self.bookmark(&format!("ref-{number}"), &mark)
+                            + &self.span(reference, &RunStyle::default());
+                        items += &docx_paragraph(None, "", &runs);
+                    }
+                    self.references = references;
+                    items
+                }
+                HTMLTag::Group { children, .. } => self.render(children)?,
+                HTMLTag::Details(summary, children) => {
+                    let bold = RunStyle {
+                        bold: true,
+                        ..RunStyle::default()
+                    };
+                    let summary = self.runs(&summary, &bold);
+                    docx_paragraph(None, "", &summary) + &self.render(children)?
+                }
+                HTMLTag::Image(ref url) => {
+                    let runs = self.image(url, &text)?;
+                    docx_paragraph(None, "", &runs)
+                }
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    let runs = self.image(url, &image)?;
+                    let caption = self.runs(&caption, &RunStyle::default());
+                    docx_paragraph(None, "<w:keepNext/>", &runs)
+                        + &docx_paragraph(Some("Caption"), "", &caption)
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    let runs = match runs.is_empty() {
+                        true => docx_run(&url, &RunStyle::default()),
+                        false => runs,
+                    };
+                    docx_paragraph(None, "", &self.hyperlink(&url, &runs))
+                }
+                HTMLTag::List => {
+                    let numbering = format!(
+                        "<w:numPr><w:ilvl w:val=\"{}\"/><w:numId w:val=\"1\"/></w:numPr>",
+                        depth.min(8)
+                    );
+                    docx_paragraph(Some("ListParagraph"), &numbering, &runs)
+                }
+                HTMLTag::OrderedList(start) => {
+                    // Like in HTML, a start number always begins a new list
+                    let id = match (list, start) {
+                        (Some(id), None) => id,
+                        _ => {
+                            self.lists.push((depth.min(8), start.unwrap_or(1)));
+                            // The bulleted list takes the first numbering
+                            self.lists.len() + 1
+                        }
+                    };
+                    list = Some(id);
+                    let numbering = format!(
+                        "<w:numPr><w:ilvl w:val=\"{}\"/><w:numId w:val=\"{id}\"/></w:numPr>",
+                        depth.min(8)
+                    );
+                    docx_paragraph(Some("ListParagraph"), &numbering, &runs)
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { "\u{2612} " } else { "\u{2610} " };
+                    let runs = docx_run(mark, &RunStyle::default()) + &runs;
+                    docx_paragraph(Some("ListParagraph"), &indent(depth + 1), &runs)
+                }
+                HTMLTag::Definition(term) => {
+                    let bold = RunStyle {
+                        bold: true,
+                        ..RunStyle::default()
+                    };
+                    let term = self.runs(&term, &bold);
+                    docx_paragraph(None, "<w:keepNext/>", &term)
+                        + &docx_paragraph(None, &indent(1), &runs)
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let style = RunStyle {
+                        bold: header,
+                        ..RunStyle::default()
+                    };
+                    let cells = cells.iter().map(|cell| self.runs(cell, &style)).collect();
+                    rows.push((header, cells));
+                    if let Some(Value::Text(next)) = values.peek()
+                        && matches!(next.tag, HTMLTag::TableRow { .. })
+                    {
+                        continue;
+                    }
+                    docx_table(std::mem::take(&mut rows))
+                }
+            };
+            output.push(xml);
+        }
+        Ok(output.join("\n"))
+    }
+
+    /// Runs of the content and the appended texts, styled by the inline elements
+    fn runs(&mut self, text: &Text, style: &RunStyle) -> String {
+        let mut style = style.clone();
+        let mut content = text.content.clone();
+        for inline in &text.inline {
+            match inline {
+                Inline::Bold => style.bold = true,
+                Inline::Italic => style.italic = true,
+                Inline::Strike => style.strike = true,
+                Inline::Code | Inline::Kbd | Inline::Math => style.code = true,
+                Inline::Sup => style.align = Some("superscript"),
+                Inline::Sub => style.align = Some("subscript"),
+                Inline::Mark => style.mark = true,
+                Inline::Small => style.small = true,
+                Inline::Abbr(title) => content += &format!(" ({title})"),
+            }
+        }
+        let mut runs = docx_run(&content, &style);
+        for span in &text.spans {
+            runs += &self.span(span, &style);
+        }
+        runs
+    }
+
+    /// Render the appended text inside the content of another one
+    fn span(&mut self, text: &Text, style: &RunStyle) -> String {
+        match &text.tag {
+            HTMLTag::Link(url) => {
+                let style = RunStyle {
+                    link: true,
+                    ..style.clone()
+                };
+                let runs = self.runs(text, &style);
+                self.hyperlink(url, &runs)
+            }
+            _ => self.runs(text, style),
+        }
+    }
+
+    /// Link around the runs, to a bookmark if the address is an anchor
+    fn hyperlink(&mut self, url: &str, runs: &str) -> String {
+        if let Some(id) = url.strip_prefix('#') {
+            return format!(
+                "<w:hyperlink w:anchor=\"{}\">{runs}</w:hyperlink>",
+                bookmark_name(id)
+            );
+        }
+        let id = self.relate("hyperlink", url.to_owned(), true);
+        format!("<w:hyperlink r:id=\"{id}\">{runs}</w:hyperlink>")
+    }
+
+    /// Bookmark around the runs, which anchors in links lead to
+    fn bookmark(&mut self, id: &str, runs: &str) -> String {
+        self.count += 1;
+        let (number, name) = (self.count, bookmark_name(id));
+        format!(
+            "<w:bookmarkStart w:id=\"{number}\" w:name=\"{name}\"/>{runs}<w:bookmarkEnd w:id=\"{number}\"/>"
+        )
+    }
+
+    /// Add a relationship from the document, returning its ID
+    fn relate(&mut self, kind: &'static str, target: String, external: bool) -> String {
+        self.relationships.push((kind, target, external));
+        format!("rId{}", self.relationships.len())
+    }
+
+    /// Drawing of the image if it's a local or embedded one Word can show, or else a link to it
+    fn image(&mut self, url: &str, image: &Text) -> Result<String, Error> {
+        let bytes = if let Some(data) = url.strip_prefix("data:") {
+            let (mime, data) = data.split_once(";base64,").unwrap_or_default();
+            STANDARD
+                .decode(data)
+                .ok()
+                .map(|bytes| (mime.to_owned(), bytes))
+        } else if let Some((path, _)) = self.assets.iter().find(|(_, name)| name == url) {
+            let bytes = std::fs::read(path).map_err(|e| ErrorKind::Io(path.clone(), e))?;
+            image_type(path).map(|mime| (mime.to_owned(), bytes))
+        } else {
+            None
+        };
+        let extension = bytes.as_ref().and_then(|(mime, _)| {
+            let image = DOCX_IMAGES.iter().find(|(_, image)| image == mime);
+            image.map(|(extension, _)| *extension)
+        });
+        let (Some((_, bytes)), Some(extension)) = (bytes, extension) else {
+            let alt = match image.content.is_empty() {
+                true => url,
+                false => &image.content,
+            };
+            let runs = docx_run(alt, &RunStyle::default());
+            return Ok(match url.starts_with("data:") {
+                true => runs,
+                false => self.hyperlink(url, &runs),
+            });
+        };
+
+        // Sizes are in pixels at 96 DPI, shrunk to the width of the page
+        let attribute = |name: &str| {
+            let attrs = image.attributes.attrs.iter();
+            let attribute = attrs.clone().find(|(attr, _)| attr == name);
+            attribute.and_then(|(_, value)| value.parse::<u64>().ok())
+        };
+        let size = image_size(&bytes).unwrap_or((480, 360));
+        let (width, height) = match (attribute("width"), attribute("height")) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, width * size.1 / size.0.max(1)),
+            (None, Some(height)) => (height * size.0 / size.1.max(1), height),
+            (None, None) => size,
+        };
+        let scale = (624.0 / width.max(1) as f64).min(1.0);
+        let emu = |pixels: u64| (pixels as f64 * scale * 9525.0) as u64;
+        let (cx, cy) = (emu(width), emu(height));
+
+        self.count += 1;
+        let number = self.count;
+        let name = format!("image{}.{extension}", self.media.len() + 1);
+        self.media.push((name.clone(), bytes));
+        let id = self.relate("image", format!("media/{name}"), false);
+        let alt = html_escape(&image.content);
+        Ok(format!(
+            "<w:r><w:drawing><wp:inline distT=\"0\" distB=\"0\" distL=\"0\" distR=\"0\"><wp:extent cx=\"{cx}\" cy=\"{cy}\"/><wp:docPr id=\"{number}\" name=\"Picture {number}\" descr=\"{alt}\"/><a:graphic xmlns:a=\"http://schemas.openxmlformats.org/drawingml/2006/main\"><a:graphicData uri=\"http://schemas.openxmlformats.org/drawingml/2006/picture\"><pic:pic xmlns:pic=\"http://schemas.openxmlformats.org/drawingml/2006/picture\"><pic:nvPicPr><pic:cNvPr id=\"{number}\" name=\"{name}\" descr=\"{alt}\"/><pic:cNvPicPr/></pic:nvPicPr><pic:blipFill><a:blip r:embed=\"{id}\"/><a:stretch><a:fillRect/></a:stretch></pic:blipFill><pic:spPr><a:xfrm><a:off x=\"0\" y=\"0\"/><a:ext cx=\"{cx}\" cy=\"{cy}\"/></a:xfrm><a:prstGeom prst=\"rect\"><a:avLst/></a:prstGeom></pic:spPr></pic:pic></a:graphicData></a:graphic></wp:inline></w:drawing></w:r>"
+        ))
+    }
+}
+
+/// Paragraph of the runs, with the style and the other properties
+fn docx_paragraph(style: Option<&str>, properties: &str, runs: &str) -> String {
+    let style = match style {
+        Some(style) => format!("<w:pStyle w:val=\"{style}\"/>"),
+        None => String::new(),
+    };
+    match (style + properties).as_str() {
+        "" => format!("<w:p>{runs}</w:p>"),
+        properties => format!("<w:p><w:pPr>{properties}</w:pPr>{runs}</w:p>"),
+    }
+}
+
+/// Run of the text, with breaks at its line breaks
+fn docx_run(text: &str, style: &RunStyle) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    let lines: Vec<String> = (text.split(LINE_BREAK))
+        .map(|line| format!("<w:t xml:space=\"preserve\">{}</w:t>", html_escape(line)))
+        .collect();
+    format!("<w:r>{}{}</w:r>", style.xml(), lines.join("<w:br/>"))
+}
+
+/// Table of the rows, repeating the header ones on each page
+fn docx_table(rows: Vec<(bool, Vec<String>)>) -> String {
+    let columns = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(0);
+    let border = |side: &str| {
+        format!("<w:{side} w:val=\"single\" w:sz=\"4\" w:space=\"0\" w:color=\"auto\"/>")
+    };
+    let borders: String = ["top", "left", "bottom", "right", "insideH", "insideV"]
+        .map(border)
+        .concat();
+    let mut xml = format!(
+        "<w:tbl><w:tblPr><w:tblW w:w=\"0\" w:type=\"auto\"/><w:tblBorders>{borders}</w:tblBorders></w:tblPr><w:tblGrid>{}</w:tblGrid>",
+        "<w:gridCol w:w=\"2000\"/>".repeat(columns)
+    );
+    for (header, mut cells) in rows {
+        cells.resize(columns, String::new());
+        xml += "<w:tr>";
+        if header {
+            xml += "<w:trPr><w:tblHeader/></w:trPr>";
+        }
+        for cell in cells {
+            xml += &format!("<w:tc>{}</w:tc>", docx_paragraph(None, "", &cell));
+        }
+        xml += "</w:tr>";
+    }
+    xml + "</w:tbl>"
+}
+
+/// Name of the bookmark for the ID, which Word limits to letters, digits and underscores
+fn bookmark_name(id: &str) -> String {
+    let name = id
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' });
+    // A leading underscore hides the bookmark from the list in Word
+    format!("_{}", name.take(39).collect::<String>())
+}
+
+/// Paragraph and character styles referred to by the document
+fn docx_styles() -> Vec<String> {
+    let mut styles = vec![
+        "<w:style w:type=\"paragraph\" w:default=\"1\" w:styleId=\"Normal\"><w:name w:val=\"Normal\"/><w:qFormat/></w:style>".to_owned(),
+    ];
+    for level in 1..=6 {
+        let size = [32, 28, 26, 24, 22, 22][level - 1];
+        styles.push(format!(
+            "<w:style w:type=\"paragraph\" w:styleId=\"Heading{level}\"><w:name w:val=\"heading {level}\"/><w:basedOn w:val=\"Normal\"/><w:next w:val=\"Normal\"/><w:qFormat/><w:pPr><w:keepNext/><w:spacing w:before=\"240\" w:after=\"120\"/><w:outlineLvl w:val=\"{}\"/></w:pPr><w:rPr><w:b/><w:sz w:val=\"{size}\"/></w:rPr></w:style>",
+            level - 1
+        ));
+    }
+    styles.extend([
+        "<w:style w:type=\"paragraph\" w:styleId=\"Quote\"><w:name w:val=\"Quote\"/><w:basedOn w:val=\"Normal\"/><w:qFormat/><w:pPr><w:pBdr><w:left w:val=\"single\" w:sz=\"12\" w:space=\"8\" w:color=\"BFBFBF\"/></w:pBdr></w:pPr><w:rPr><w:i/><w:color w:val=\"595959\"/></w:rPr></w:style>",
+        "<w:style w:type=\"paragraph\" w:styleId=\"Code\"><w:name w:val=\"Code\"/><w:basedOn w:val=\"Normal\"/><w:pPr><w:shd w:val=\"clear\" w:color=\"auto\" w:fill=\"F2F2F2\"/><w:spacing w:after=\"160\" w:line=\"240\" w:lineRule=\"auto\"/></w:pPr><w:rPr><w:rFonts w:ascii=\"Courier New\" w:hAnsi=\"Courier New\" w:cs=\"Courier New\"/><w:sz w:val=\"20\"/></w:rPr></w:style>",
+        "<w:style w:type=\"paragraph\" w:styleId=\"Caption\"><w:name w:val=\"caption\"/><w:basedOn w:val=\"Normal\"/><w:next w:val=\"Normal\"/><w:qFormat/><w:rPr><w:i/><w:sz w:val=\"18\"/></w:rPr></w:style>",
+        "<w:style w:type=\"paragraph\" w:styleId=\"ListParagraph\"><w:name w:val=\"List Paragraph\"/><w:basedOn w:val=\"Normal\"/><w:qFormat/><w:pPr><w:spacing w:after=\"60\"/><w:contextualSpacing/></w:pPr></w:style>",
+        "<w:style w:type=\"character\" w:styleId=\"Hyperlink\"><w:name w:val=\"Hyperlink\"/><w:rPr><w:color w:val=\"0563C1\"/><w:u w:val=\"single\"/></w:rPr></w:style>",
+    ].map(str::to_owned));
+    styles
+}
+
+/// Numbering of the bulleted lists, and of each ordered list from its level and start number
+fn docx_numbering(lists: &[(usize, i32)]) -> String {
+    let levels = |format: &str| {
+        let levels = (0..9).map(|level| {
+            let text = match format {
+                "bullet" => ["\u{2022}", "\u{25e6}", "\u{25aa}"][level % 3].to_owned(),
+                _ => format!("%{}.", level + 1),
+            };
+            format!(
+                "<w:lvl w:ilvl=\"{level}\"><w:start w:val=\"1\"/><w:numFmt w:val=\"{format}\"/><w:lvlText w:val=\"{text}\"/><w:lvlJc w:val=\"left\"/><w:pPr><w:ind w:left=\"{}\" w:hanging=\"360\"/></w:pPr></w:lvl>",
+                720 * (level + 1)
+            )
+        });
+        levels.collect::<String>()
+    };
+    let mut numbering = vec![
+        format!(
+            "<w:abstractNum w:abstractNumId=\"0\">{}</w:abstractNum>",
+            levels("bullet")
+        ),
+        format!(
+            "<w:abstractNum w:abstractNumId=\"1\">{}</w:abstractNum>",
+            levels("decimal")
+        ),
+        "<w:num w:numId=\"1\"><w:abstractNumId w:val=\"0\"/></w:num>".to_owned(),
+    ];
+    for (index, (level, start)) in lists.iter().enumerate() {
+        numbering.push(format!(
+            "<w:num w:numId=\"{}\"><w:abstractNumId w:val=\"1\"/><w:lvlOverride w:ilvl=\"{level}\"><w:startOverride w:val=\"{start}\"/></w:lvlOverride></w:num>",
+            index + 2
+        ));
+    }
+    format!(
+        "{XML_DECLARATION}\n<w:numbering xmlns:w=\"http://schemas.openxmlformats.org/wordprocessingml/2006/main\">\n{}\n</w:numbering>\n",
+        numbering.join("\n")
+    )
+}
+
+/// Pixel size of a PNG, GIF, BMP or JPEG image read from its header
+fn image_size(bytes: &[u8]) -> Option<(u64, u64)> {
+    let be = |at: usize, len: usize| {
+        let bytes = bytes.get(at..at + len)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |value, byte| value << 8 | u64::from(*byte)),
+        )
+    };
+    let le = |at: usize, len: usize| {
+        let bytes = bytes.get(at..at + len)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, byte| value << 8 | u64::from(*byte)),
+        )
+    };
+    if bytes.starts_with(b"\x89PNG") {
+        return Some((be(16, 4)?, be(20, 4)?));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((le(6, 2)?, le(8, 2)?));
+    }
+    if bytes.starts_with(b"BM") {
+        let height = le(22, 4)? as u32 as i32;
+        return Some((le(18, 4)?, u64::from(height.unsigned_abs())));
+    }
+    if bytes.starts_with(b"\xff\xd8") {
+        let mut at = 2;
+        while bytes.get(at) == Some(&0xff) {
+            let marker = *bytes.get(at + 1)?;
+            // Start of frame markers, except those of the Huffman and arithmetic tables
+            if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                return Some((be(at + 7, 2)?, be(at + 5, 2)?));
+            }
+            at += 2 + be(at + 2, 2)? as usize;
+        }
+    }
+    None
+}
+
+/// Identifier of the book, which is its address if published or else derived from its files
+fn book_id(canonical: &Option<String>, files: &[(String, Vec<u8>)]) -> String {
+    match canonical {
+        Some(url) => html_escape(url),
+        None => uuid_urn(files),
+    }
+}
+
+/// Name in the `urn:uuid` namespace derived from the value, for things without an address
+fn uuid_urn(value: impl Hash) -> String {
+    let mut hashers = [DefaultHasher::new(), DefaultHasher::new()];
+    for (index, hasher) in hashers.iter_mut().enumerate() {
+        index.hash(hasher);
+        value.hash(hasher);
+    }
+    let [high, low] = hashers.map(|hasher| hasher.finish());
+    let hex = format!("{high:016x}{low:016x}");
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Turn the HTML into XHTML, closing the void elements and giving values to boolean attributes
+fn xhtml(html: &str) -> String {
+    let mut result = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        result += &rest[..start];
+        rest = &rest[start..];
+        let end = tag_end(rest);
+        let tag = rest[1..end].trim_end_matches('>');
+        rest = &rest[end..];
+        let name = (tag.split(|c: char| !c.is_ascii_alphanumeric()).next()).unwrap_or_default();
+        if name.is_empty() || tag.ends_with('/') {
+            result += &format!("<{tag}>");
+            continue;
+        }
+        let mut attributes = Vec::new();
+        let mut attribute = String::new();
+        let mut quote = None;
+        for c in tag[name.len()..].chars() {
+            match (quote, c) {
+                (None, c) if c.is_whitespace() => {
+                    attributes
+                        .extend((!attribute.is_empty()).then(|| std::mem::take(&mut attribute)));
+                    continue;
+                }
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(open), _) if open == c => quote = None,
+                _ => {}
+            }
+            attribute.push(c);
+        }
+        attributes.extend((!attribute.is_empty()).then_some(attribute));
+        result += &format!("<{name}");
+        for attribute in attributes {
+            match attribute.contains('=') {
+                true => result += &format!(" {attribute}"),
+                false => result += &format!(" {attribute}=\"{attribute}\""),
+            }
+        }
+        result += if VOID_ELEMENTS.contains(&name) {
+            "/>"
+        } else {
+            ">"
+        };
+    }
+    result + rest
+}
+
+/// Render the document as Gemini gemtext, where links have lines of their own
+fn gemtext(stack: Stack) -> String {
+    let mut data = stack.data;
+    let headings = heading_ids(&mut data);
+    let mut gemtext = Gemtext {
+        references: stack.references,
+        ..Gemtext::default()
+    };
+    let toc = headings
+        .iter()
+        .map(|(_, text)| format!("* {}", text.plain()));
+    gemtext.toc = toc.collect::<Vec<_>>().join("\n");
+    let mut body = gemtext.render(data);
+    if !gemtext.footnotes.is_empty() {
+        body += "\n\n---";
+        for (index, note) in std::mem::take(&mut gemtext.footnotes).iter().enumerate() {
+            let mut links = Vec::new();
+            body += &format!("\n[{}] {}", index + 1, inline_gemtext(note, &mut links));
+            body += &link_lines(links);
+        }
+    }
+    body + "\n"
+}
+
+/// State shared while rendering gemtext, like `Html`
+#[derive(Default)]
+struct Gemtext {
+    footnotes: Vec<Text>,
+    references: Vec<Text>,
+    toc: String,
+}
+
+impl Gemtext {
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output = String::new();
+        let mut previous = None;
+        let mut numbers: Vec<i32> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        // Links inside the text, listed on the lines after it
+        let mut links = Vec::new();
+        let mut values = values.into_iter().peekable();
+        while let Some(value) = values.next() {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut content = inline_gemtext(&text, &mut links);
+            for note in text.footnotes {
+                self.footnotes.push(note);
+                content += &format!("[{}]", self.footnotes.len());
+            }
+            // Gemtext has one line for each paragraph and no nested lists
+            let content = content.replace('\n', " ");
+            let depth = text.depth;
+            if !matches!(text.tag, HTMLTag::OrderedList(_)) {
+                numbers.clear();
+            }
+
+            let (block, gemtext) = match text.tag {
+                HTMLTag::Paragraph => (None, content),
+                HTMLTag::Heading(level) => {
+                    let level = level.clamp(1, 3) as usize;
+                    (None, format!("{} {content}", "#".repeat(level)))
+                }
+                HTMLTag::Link(url) => (None, format!("=> {url} {content}").trim_end().to_owned()),
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = format!("> {content}");
+                    if let Some(source) = source {
+                        let source = span_gemtext(&source, &mut links);
+                        quote += &format!("\n> \u{2014} {source}");
+                    }
+                    (Some(Block::Quote), quote)
+                }
+                HTMLTag::CodeBlock(language) => (None, preformatted(&text.content, &language)),
+                HTMLTag::Diagram => (None, preformatted(&text.content, "mermaid")),
+                HTMLTag::Math => (None, preformatted(&text.content, "latex")),
+                HTMLTag::Rule => (None, "---".to_owned()),
+                HTMLTag::Break | HTMLTag::SlideBreak | HTMLTag::RawHtml => continue,
+                HTMLTag::Toc => (None, self.toc.clone()),
+                HTMLTag::Bibliography => {
+                    let items = (self.references.iter().enumerate()).map(|(index, reference)| {
+                        let mut links = Vec::new();
+                        let item = span_gemtext(reference, &mut links);
+                        format!("[{}] {item}{}", index + 1, link_lines(links))
+                    });
+                    (None, items.collect::<Vec<_>>().join("\n"))
+                }
+                HTMLTag::Group { children, .. } => (None, self.render(children)),
+                HTMLTag::Details(summary, children) => {
+                    let summary = inline_gemtext(&summary, &mut links);
+                    (None, format!("{summary}\n\n{}", self.render(children)))
+                }
+                HTMLTag::Image(url) => (None, image_line(&url, &content)),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    let caption = inline_gemtext(&caption, &mut links);
+                    (None, image_line(url, &caption))
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    (None, format!("=> {url} {content}").trim_end().to_owned())
+                }
+                HTMLTag::List => (Some(Block::List), format!("* {content}")),
+                HTMLTag::OrderedList(start) => {
+                    numbers.resize(depth + 1, 0);
+                    let number = start.unwrap_or(numbers[depth] + 1);
+                    numbers[depth] = number;
+                    (Some(Block::List), format!("* {number}. {content}"))
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { 'x' } else { ' ' };
+                    (Some(Block::List), format!("* [{mark}] {content}"))
+                }
+                HTMLTag::Definition(term) => {
+                    let term = inline_gemtext(&term, &mut links);
+                    (None, format!("{term}\n{content}"))
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let cells = (cells.iter())
+                        .map(|cell| inline_gemtext(cell, &mut links))
+                        .collect();
+                    rows.push((header, cells));
+                    if let Some(Value::Text(next)) = values.peek()
+                        && matches!(next.tag, HTMLTag::TableRow { .. })
+                    {
+                        continue;
+                    }
+                    (None, preformatted(&table(std::mem::take(&mut rows)), ""))
+                }
+            };
+            if !output.is_empty() {
+                output += match block {
+                    Some(Block::Quote) if depth > 0 && previous == block => "\n",
+                    Some(Block::List) if previous == block => "\n",
+                    _ => "\n\n",
+                };
+            }
+            output += &gemtext;
+            output += &link_lines(std::mem::take(&mut links));
+            previous = block;
+        }
+        output
+    }
+}
+
+/// Render the content of the text with its appended ones, collecting their links
+fn inline_gemtext(text: &Text, links: &mut Vec<(String, String)>) -> String {
+    let mut content = text.content.replace(LINE_BREAK, "\n");
+    for span in &text.spans {
+        content += &span_gemtext(span, links);
+    }
+    (text.inline.iter()).fold(content, |content, inline| match inline {
+        Inline::Abbr(title) => format!("{content} ({title})"),
+        _ => content,
+    })
+}
+
+/// Render the appended text inside the content of another one, collecting its link
+fn span_gemtext(text: &Text, links: &mut Vec<(String, String)>) -> String {
+    let content = inline_gemtext(text, links);
+    // Anchors in the document, such as citations, lead nowhere outside of it
+    if let HTMLTag::Link(url) = &text.tag
+        && !url.starts_with('#')
+    {
+        links.push((url.clone(), content.replace('\n', " ")));
+    }
+    content
+}
+
+/// Link lines following a text for the links inside it
+fn link_lines(links: Vec<(String, String)>) -> String {
+    let lines = links
+        .into_iter()
+        .map(|(url, label)| format!("\n=> {url} {label}"));
+    lines.map(|line| line.trim_end().to_owned()).collect()
+}
+
+/// Link line to the image, which clients may show inline, or its description if embedded
+fn image_line(url: &str, alt: &str) -> String {
+    match url.starts_with("data:") {
+        true => alt.to_owned(),
+        false => format!("=> {url} {alt}").trim_end().to_owned(),
+    }
+}
+
+/// Preformatted block, whose alt text tells the language
+fn preformatted(code: &str, alt: &str) -> String {
+    format!("```{alt}\n{}\n```", code.replace(LINE_BREAK, "\n"))
+}
+
+/// Render the document as a DocBook article, with a section for each heading
+fn docbook(stack: Stack) -> String {
+    let mut data = stack.data;
+    heading_ids(&mut data);
+    let mut docbook = DocBook {
+        references: stack.references,
+    };
+
+    let mut info = Vec::new();
+    if let Some(title) = &stack.title {
+        info.push(format!("<title>{}</title>", html_escape(title)));
+    }
+    for (name, content) in &stack.meta {
+        let content = html_escape(content);
+        match name.as_str() {
+            "author" => info.push(format!(
+                "<author><personname>{content}</personname></author>"
+            )),
+            "description" => info.push(format!("<abstract><para>{content}</para></abstract>")),
+            _ => {}
+        }
+    }
+    if let Some(published) = stack.published {
+        info.push(format!(
+            "<pubdate>{}</pubdate>",
+            published.format("%Y-%m-%d")
+        ));
+    }
+
+    let mut body = Vec::new();
+    if !info.is_empty() {
+        body.push(format!("<info>{}</info>", info.join("")));
+    }
+    // Levels of the sections open around the values, which each heading closes down to its own
+    let mut sections: Vec<i32> = Vec::new();
+    let mut blocks = Vec::new();
+    for value in data {
+        let Value::Text(text) = &value else {
+            continue;
+        };
+        let HTMLTag::Heading(level) = text.tag else {
+            blocks.push(value);
+            continue;
+        };
+        body.push(docbook.render(std::mem::take(&mut blocks)));
+        while sections.last().is_some_and(|open| *open >= level) {
+            body.push("</section>".to_owned());
+            sections.pop();
+        }
+        let id = text.attributes.id.as_deref().unwrap_or_default();
+        body.push(format!(
+            "<section xml:id=\"{}\">\n<title>{}</title>",
+            xml_id(id),
+            docbook.content(text)
+        ));
+        sections.push(level);
+    }
+    body.push(docbook.render(blocks));
+    body.extend(sections.iter().map(|_| "</section>".to_owned()));
+    body.retain(|part| !part.is_empty());
+
+    let lang = match &stack.lang {
+        Some(lang) => format!(" xml:lang=\"{}\"", html_escape(lang)),
+        None => String::new(),
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<article xmlns=\"http://docbook.org/ns/docbook\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" version=\"5.0\"{lang}>\n{}\n</article>\n",
+        body.join("\n")
+    )
+}
+
+/// State shared while rendering DocBook, like `Html`
+struct DocBook {
+    references: Vec<Text>,
+}
+
+impl DocBook {
+    /// Render the blocks between headings, or inside groups where headings stand on their own
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output: Vec<String> = Vec::new();
+        // Element and depth of the lists open around the item, each ending with an open entry
+        let mut lists: Vec<(&str, usize)> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        let mut values = values.into_iter().peekable();
+        while let Some(value) = values.next() {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let content = self.content(&text);
+
+            let list = match text.tag {
+                HTMLTag::List | HTMLTag::Task(_) => Some(("itemizedlist", text.depth)),
+                HTMLTag::OrderedList(_) => Some(("orderedlist", text.depth)),
+                HTMLTag::Definition(_) => Some(("variablelist", 0)),
+                _ => None,
+            };
+            // Like in HTML, a start number always begins a new list
+            let restart = matches!(text.tag, HTMLTag::OrderedList(Some(_)));
+            let depth = list.map_or(0, |(_, depth)| depth);
+            while let Some(&(element, open)) = lists.last()
+                && (list.is_none()
+                    || open > depth
+                    || open == depth && (Some((element, open)) != list || restart))
+            {
+                output.push(format!("{}</{element}>", list_entry_end(element)));
+                lists.pop();
+            }
+            if let Some((element, depth)) = list {
+                if lists.last() == Some(&(element, depth)) {
+                    output.push(list_entry_end(element).to_owned());
+                } else {
+                    let start = match text.tag {
+                        HTMLTag::OrderedList(Some(start)) => format!(" startingnumber=\"{start}\""),
+                        _ => String::new(),
+                    };
+                    output.push(format!("<{element}{start}>"));
+                    lists.push((element, depth));
+                }
+            }
+
+            let xml = match text.tag {
+                HTMLTag::Paragraph => format!("<para>{content}</para>"),
+                HTMLTag::Heading(level) => {
+                    format!(
+                        "<bridgehead renderas=\"sect{}\">{content}</bridgehead>",
+                        level.clamp(1, 5)
+                    )
+                }
+                HTMLTag::Link(url) => {
+                    let content = match content.is_empty() {
+                        true => html_escape(&url),
+                        false => content,
+                    };
+                    format!("<para>{}</para>", link_docbook(&url, &content))
+                }
+                HTMLTag::BlockQuote(source) => {
+                    let attribution = match source {
+                        Some(source) => {
+                            format!("<attribution>{}</attribution>", self.span(&source))
+                        }
+                        None => String::new(),
+                    };
+                    let mut quote =
+                        format!("<blockquote>{attribution}<para>{content}</para></blockquote>");
+                    for _ in 0..text.depth {
+                        quote = format!("<blockquote>{quote}</blockquote>");
+                    }
+                    quote
+                }
+                HTMLTag::CodeBlock(language) => {
+                    let language = match language.is_empty() {
+                        true => String::new(),
+                        false => format!(" language=\"{}\"", html_escape(&language)),
+                    };
+                    let code = html_escape(&text.content.replace(LINE_BREAK, "\n"));
+                    format!("<programlisting{language}>{code}</programlisting>")
+                }
+                HTMLTag::Diagram => {
+                    let code = html_escape(&text.content.replace(LINE_BREAK, "\n"));
+                    format!("<programlisting language=\"mermaid\">{code}</programlisting>")
+                }
+                HTMLTag::Math => {
+                    let math = html_escape(&text.content.replace(LINE_BREAK, "\n"));
+                    format!("<informalequation><mathphrase>{math}</mathphrase></informalequation>")
+                }
+                // DocBook has no rules or breaks between blocks, and the toolchain makes its own contents
+                HTMLTag::Rule
+                | HTMLTag::Break
+                | HTMLTag::Toc
+                | HTMLTag::SlideBreak
+                | HTMLTag::RawHtml => continue,
+                HTMLTag::Bibliography => {
+                    let entries = (self.references.iter().enumerate()).map(|(index, reference)| {
+                        format!(
+                            "<bibliomixed xml:id=\"{}\">{}</bibliomixed>",
+                            xml_id(&format!("ref-{}", index + 1)),
+                            self.span(reference)
+                        )
+                    });
+                    let entries = entries.collect::<Vec<_>>().join("\n");
+                    format!("<bibliolist>\n{entries}\n</bibliolist>")
+                }
+                HTMLTag::Group { children, .. } => self.render(children),
+                HTMLTag::Details(summary, children) => {
+                    let summary = self.content(&summary);
+                    format!(
+                        "<sidebar>\n<title>{summary}</title>\n{}\n</sidebar>",
+                        self.render(children)
+                    )
+                }
+                HTMLTag::Image(url) => media_docbook("image", &url, &text.content),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    format!(
+                        "<figure>\n<title>{}</title>\n{}\n</figure>",
+                        self.content(&caption),
+                        media_docbook("image", url, &image.content)
+                    )
+                }
+                HTMLTag::Audio(url) => media_docbook("audio", &url, &text.content),
+                HTMLTag::Video { url, .. } => media_docbook("video", &url, &text.content),
+                HTMLTag::Embed(url) => {
+                    let content = match content.is_empty() {
+                        true => html_escape(&url),
+                        false => content,
+                    };
+                    format!("<para>{}</para>", link_docbook(&url, &content))
+                }
+                HTMLTag::List | HTMLTag::OrderedList(_) => {
+                    format!("<listitem><para>{content}</para>")
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { "\u{2612}" } else { "\u{2610}" };
+                    format!("<listitem><para>{mark} {content}</para>")
+                }
+                HTMLTag::Definition(term) => format!(
+                    "<varlistentry><term>{}</term><listitem><para>{content}</para>",
+                    self.content(&term)
+                ),
+                HTMLTag::TableRow { cells, header } => {
+                    let cells = cells.iter().map(|cell| self.content(cell)).collect();
+                    rows.push((header, cells));
+                    if let Some(Value::Text(next)) = values.peek()
+                        && matches!(next.tag, HTMLTag::TableRow { .. })
+                    {
+                        continue;
+                    }
+                    table_docbook(std::mem::take(&mut rows))
+                }
+            };
+            output.push(xml);
+        }
+        for (element, _) in lists.into_iter().rev() {
+            output.push(format!("{}</{element}>", list_entry_end(element)));
+        }
+        output.join("\n")
+    }
+
+    /// Content of the text with its inline elements, appended texts and footnotes
+    fn content(&self, text: &Text) -> String {
+        let mut content = html_escape(&text.content.replace(LINE_BREAK, "\n"));
+        for span in &text.spans {
+            content += &self.span(span);
+        }
+        let mut content = (text.inline.iter()).fold(content, |content, inline| match inline {
+            Inline::Bold => format!("<emphasis role=\"strong\">{content}</emphasis>"),
+            Inline::Italic => format!("<emphasis>{content}</emphasis>"),
+            Inline::Strike => format!("<emphasis role=\"strikethrough\">{content}</emphasis>"),
+            Inline::Code => format!("<code>{content}</code>"),
+            Inline::Sup => format!("<superscript>{content}</superscript>"),
+            Inline::Sub => format!("<subscript>{content}</subscript>"),
+            Inline::Kbd => format!("<userinput>{content}</userinput>"),
+            Inline::Mark => format!("<emphasis role=\"marked\">{content}</emphasis>"),
+            Inline::Small => format!("<phrase role=\"small\">{content}</phrase>"),
+            Inline::Math => {
+                format!("<inlineequation><mathphrase>{content}</mathphrase></inlineequation>")
+            }
+            Inline::Abbr(title) => format!("<abbrev>{content}</abbrev> ({})", html_escape(title)),
+        });
+        for note in &text.footnotes {
+            content += &format!("<footnote><para>{}</para></footnote>", self.content(note));
+        }
+        content
+    }
+
+    /// Render the appended text inside the content of another one
+    fn span(&self, text: &Text) -> String {
+        let content = self.content(text);
+        match &text.tag {
+            HTMLTag::Link(url) => link_docbook(url, &content),
+            _ => content,
+        }
+    }
+}
+
+/// End of the entry left open in the list, before the next one or the end of the list
+fn list_entry_end(element: &str) -> &'static str {
+    match element {
+        "variablelist" => "</listitem></varlistentry>",
+        _ => "</listitem>",
+    }
+}
+
+/// Link to the address, or to the element with the ID if it's an anchor
+fn link_docbook(url: &str, content: &str) -> String {
+    match url.strip_prefix('#') {
+        Some(id) => format!("<link linkend=\"{}\">{content}</link>", xml_id(id)),
+        None => format!("<link xlink:href=\"{}\">{content}</link>", html_escape(url)),
+    }
+}
+
+/// Media object of the kind like `image`, with the description as its text alternative
+fn media_docbook(kind: &str, url: &str, alt: &str) -> String {
+    let alt = match alt.is_empty() {
+        true => String::new(),
+        false => format!(
+            "<textobject><phrase>{}</phrase></textobject>",
+            html_escape(alt)
+        ),
+    };
+    format!(
+        "<mediaobject><{kind}object><{kind}data fileref=\"{}\"/></{kind}object>{alt}</mediaobject>",
+        html_escape(url)
+    )
+}
+
+/// Table of the rows, with the leading header rows as its head
+fn table_docbook(rows: Vec<(bool, Vec<String>)>) -> String {
+    let columns = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(1);
+    let row = |cells: &[String]| {
+        let entries: String = (cells.iter())
+            .map(|cell| format!("<entry>{cell}</entry>"))
+            .collect();
+        format!("<row>{entries}</row>")
+    };
+    let head = rows.iter().take_while(|(header, _)| *header).count();
+    // The body needs a row, which the head gives up if it's all there is
+    let head = if head == rows.len() { 0 } else { head };
+    let (header, body) = rows.split_at(head);
+    let header: Vec<String> = header.iter().map(|(_, cells)| row(cells)).collect();
+    let body: Vec<String> = body.iter().map(|(_, cells)| row(cells)).collect();
+    let header = match header.is_empty() {
+        true => String::new(),
+        false => format!("\n<thead>\n{}\n</thead>", header.join("\n")),
+    };
+    format!(
+        "<informaltable>\n<tgroup cols=\"{columns}\">{header}\n<tbody>\n{}\n</tbody>\n</tgroup>\n</informaltable>",
+        body.join("\n")
+    )
+}
+
+/// ID valid as an XML name, which can't begin with a digit or a hyphen
+fn xml_id(id: &str) -> String {
+    let id: String = (id.chars())
+        .map(
+            |c| match c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    match id.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        true => id,
+        false => format!("_{id}"),
+    }
+}
+
+/// Render the document as a manual page in roff, for `man` to show
+fn man(stack: Stack) -> String {
+    let mut data = stack.data;
+    heading_ids(&mut data);
+    let mut man = Man {
+        references: stack.references,
+        ..Man::default()
+    };
+    let body = man.render(data);
+
+    let title = stack.title.as_deref().unwrap_or("Untitled");
+    let date = (stack.published).map(|date| date.format("%Y-%m-%d").to_string());
+    let mut page = vec![format!(
+        ".TH \"{}\" 1 \"{}\"",
+        roff_escape(&title.to_uppercase()).replace('"', "\\(dq"),
+        date.unwrap_or_default()
+    )];
+    // Tables need the preprocessor, which the first line asks `man` for
+    if man.tables {
+        page.insert(0, "'\\\" t".to_owned());
+    }
+    let description = stack.meta.iter().find(|(name, _)| name == "description");
+    if let (Some(title), Some((_, description))) = (&stack.title, description) {
+        page.push(".SH NAME".to_owned());
+        page.push(format!(
+            "{} \\- {}",
+            roff_escape(title),
+            roff_escape(description)
+        ));
+    }
+    page.push(body);
+    if !man.footnotes.is_empty() {
+        page.push(".SH NOTES".to_owned());
+        for (index, note) in man.footnotes.iter().enumerate() {
+            page.push(format!(".IP {}. 4\n{}", index + 1, inline_roff(note)));
+        }
+    }
+    page.retain(|part| !part.is_empty());
+    page.join("\n") + "\n"
+}
+
+/// State shared while rendering roff, like `Html`
+#[derive(Default)]
+struct Man {
+    footnotes: Vec<Text>,
+    references: Vec<Text>,
+    /// Whether a table needs the `tbl` preprocessor
+    tables: bool,
+}
+
+impl Man {
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output: Vec<String> = Vec::new();
+        // Indentations opened by `.RS` for nested lists, and numbers of the ordered ones
+        let mut indents = 0;
+        let mut numbers: Vec<i32> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        let mut values = values.into_iter().peekable();
+        while let Some(value) = values.next() {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut content = inline_roff(&text);
+            for note in text.footnotes {
+                self.footnotes.push(note);
+                content += &format!("[{}]", self.footnotes.len());
+            }
+
+            let listed = matches!(
+                text.tag,
+                HTMLTag::List | HTMLTag::OrderedList(_) | HTMLTag::Task(_)
+            );
+            let depth = if listed { text.depth } else { 0 };
+            while indents > depth {
+                output.push(".RE".to_owned());
+                indents -= 1;
+            }
+            while indents < depth {
+                output.push(".RS".to_owned());
+                indents += 1;
+            }
+            if !matches!(text.tag, HTMLTag::OrderedList(_)) {
+                numbers.clear();
+            }
+
+            let roff = match text.tag {
+                HTMLTag::Paragraph => format!(".PP\n{content}"),
+                HTMLTag::Heading(level) => {
+                    let content = content.replace('"', "\\(dq").replace('\n', " ");
+                    match level {
+                        ..=1 => format!(".SH \"{content}\""),
+                        _ => format!(".SS \"{content}\""),
+                    }
+                }
+                HTMLTag::Link(url) => link_roff(&url, &content),
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = format!(".PP\n{content}");
+                    if let Some(source) = source {
+                        quote += &format!("\n.br\n\\(em {}", span_roff(&source));
+                    }
+                    for _ in 0..=text.depth {
+                        quote = format!(".RS 4\n{quote}\n.RE");
+                    }
+                    quote
+                }
+                HTMLTag::CodeBlock(_) | HTMLTag::Diagram | HTMLTag::Math => {
+                    let code = roff_escape(&text.content.replace(LINE_BREAK, "\n"));
+                    format!(".PP\n.RS 4\n.nf\n{code}\n.fi\n.RE")
+                }
+                HTMLTag::Rule => ".PP\n\\l'\\n(.lu'".to_owned(),
+                HTMLTag::Break => ".br".to_owned(),
+                // A manual page is short enough to go without contents
+                HTMLTag::Toc | HTMLTag::SlideBreak | HTMLTag::RawHtml => continue,
+                HTMLTag::Bibliography => {
+                    let items = (self.references.iter().enumerate()).map(|(index, reference)| {
+                        format!(".IP [{}] 5\n{}", index + 1, span_roff(reference))
+                    });
+                    items.collect::<Vec<_>>().join("\n")
+                }
+                HTMLTag::Group { children, .. } => self.render(children),
+                HTMLTag::Details(summary, children) => {
+                    let summary = inline_roff(&summary);
+                    format!(".PP\n\\fB{summary}\\fP\n{}", self.render(children))
+                }
+                HTMLTag::Image(url) => format!(".PP\n{}", image_roff(&url, &content)),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    let caption = inline_roff(&caption);
+                    format!(".PP\n{}\n.br\n\\fI{caption}\\fP", image_roff(url, &caption))
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    link_roff(&url, &content)
+                }
+                HTMLTag::List => format!(".IP \\(bu 2\n{content}"),
+                HTMLTag::OrderedList(start) => {
+                    numbers.resize(text.depth + 1, 0);
+                    let number = start.unwrap_or(numbers[text.depth] + 1);
+                    numbers[text.depth] = number;
+                    format!(".IP {number}. 4\n{content}")
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { "[x]" } else { "[ ]" };
+                    format!(".IP \"{mark}\" 4\n{content}")
+                }
+                HTMLTag::Definition(term) => {
+                    format!(".TP\n\\fB{}\\fP\n{content}", inline_roff(&term))
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let cells: Vec<String> = cells.iter().map(inline_roff).collect();
+                    let cells = match header {
+                        true => (cells.into_iter())
+                            .map(|cell| format!("\\fB{cell}\\fP"))
+                            .collect(),
+                        false => cells,
+                    };
+                    rows.push((header, cells));
+                    if let Some(Value::Text(next)) = values.peek()
+                        && matches!(next.tag, HTMLTag::TableRow { .. })
+                    {
+                        continue;
+                    }
+                    self.tables = true;
+                    table_roff(std::mem::take(&mut rows))
+                }
+            };
+            output.push(roff);
+        }
+        while indents > 0 {
+            output.push(".RE".to_owned());
+            indents -= 1;
+        }
+        output.join("\n")
+    }
+}
+
+/// Table of the rows for the `tbl` preprocessor, boxed like in HTML
+fn table_roff(rows: Vec<(bool, Vec<String>)>) -> String {
+    let columns = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(0);
+    let mut lines = vec![
+        ".PP".to_owned(),
+        ".TS".to_owned(),
+        "allbox;".to_owned(),
+        format!("{}.", vec!["l"; columns].join(" ")),
+    ];
+    for (_, mut cells) in rows {
+        cells.resize(columns, String::new());
+        let cells: Vec<String> = cells
+            .iter()
+            .map(|cell| cell.replace(['\t', '\n'], " "))
+            .collect();
+        lines.push(cells.join("\t"));
+    }
+    lines.push(".TE".to_owned());
+    lines.join("\n")
+}
+
+/// Render the content of the text with its inline elements as roff
+fn inline_roff(text: &Text) -> String {
+    let mut content = roff_escape(&text.content).replace(LINE_BREAK, "\n.br\n");
+    content.extend(text.spans.iter().map(span_roff));
+    (text.inline.iter()).fold(content, |content, inline| match inline {
+        Inline::Bold | Inline::Code | Inline::Kbd => format!("\\fB{content}\\fP"),
+        Inline::Italic => format!("\\fI{content}\\fP"),
+        Inline::Abbr(title) => format!("{content} ({})", roff_escape(title)),
+        _ => content,
+    })
+}
+
+/// Render the appended text inside the content of another one as roff
+fn span_roff(text: &Text) -> String {
+    let content = inline_roff(text);
+    match &text.tag {
+        // Anchors in the document, such as citations, lead nowhere outside of it
+        HTMLTag::Link(url) if !url.starts_with('#') => {
+            format!("{content} \\(la{}\\(ra", roff_escape(url))
+        }
+        _ => content,
+    }
+}
+
+/// Link paragraph, with the address set apart by the `.UR` macro
+fn link_roff(url: &str, content: &str) -> String {
+    if content.is_empty() {
+        return format!(".PP\n.UR {}\n.UE", roff_escape(url));
+    }
+    format!(".PP\n.UR {}\n{content}\n.UE", roff_escape(url))
+}
+
+/// Description of the image with its address, as a terminal can't show it
+fn image_roff(url: &str, alt: &str) -> String {
+    let alt = if alt.is_empty() { "Image" } else { alt };
+    match url.starts_with("data:") {
+        true => format!("[{alt}]"),
+        false => format!("[{alt}] \\(la{}\\(ra", roff_escape(url)),
+    }
+}
+
+/// Escape the backslashes, and the periods and apostrophes which would begin a request
+fn roff_escape(text: &str) -> String {
+    let mut result = String::new();
+    let mut line_start = true;
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\e"),
+            '.' | '\'' if line_start => {
+                result.push_str("\\&");
+                result.push(c);
+            }
+            _ => result.push(c),
+        }
+        line_start = c == '\n' || c == LINE_BREAK;
+    }
+    result
 }
 
-fn main() {
-    let cli = Cli::parse();
-    let filename = Path::new(&cli.path);
-
-    macro_rules! fault {
-        ($msg: literal) => {
-            eprintln!("Failed to {}", $msg);
-            return;
-        };
-    }
+/// Evaluated document written by `--format json`
+#[derive(Serialize)]
+struct Document {
+    title: Option<String>,
+    lang: Option<String>,
+    canonical: Option<String>,
+    favicon: Option<String>,
+    theme: Option<String>,
+    /// Name and content of the `<meta>` tags
+    meta: Vec<(String, String)>,
+    /// Works cited by `cite`, numbered from 1
+    references: Vec<Text>,
+    body: Vec<Text>,
+}
 
-    let Ok(source) = read_to_string(filename) else {
-        fault!("read source file");
-    };
-    let Some(html) = stav(&source) else {
-        fault!("compile StaV code");
-    };
-    let Ok(mut output_file) = File::create(filename.with_extension("html")) else {
-        fault!("create HTML file");
-    };
-    let Ok(_) = output_file.write_all(html.as_bytes()) else {
-        fault!("write out to the file");
+/// Serialize the document model as JSON, with the heading IDs resolved as in HTML
+fn json(stack: Stack) -> Vec<u8> {
+    let mut data = stack.data;
+    heading_ids(&mut data);
+    let document = Document {
+        title: stack.title,
+        lang: stack.lang,
+        canonical: stack.canonical,
+        favicon: stack.favicon,
+        theme: stack.theme,
+        meta: stack.meta,
+        references: stack.references,
+        body: (data.into_iter())
+            .filter_map(|value| match value {
+                Value::Text(text) => Some(text),
+                _ => None,
+            })
+            .collect(),
     };
+    // Serializing only fails for maps with non-string keys, which the document has none of
+    let mut json = serde_json::to_vec_pretty(&document).unwrap_or_default();
+    json.push(b'\n');
+    json
 }
 
-fn stav(source: &str) -> Option<String> {
-    let tokens = tokenize(source)?
-        .iter()
-        .filter(|x| !x.trim().is_empty())
-        .map(|x| Node::parse(x.trim()))
-        .collect::<Option<Vec<Node>>>()?;
-    let mut stack: Stack = Stack {
-        data: Vec::new(),
-        scope: HashMap::new(),
-        title: None,
-        theme: None,
+/// Column at which the plain text is wrapped
+const TEXT_WIDTH: usize = 72;
+
+/// Render the document as plain text, for emails and terminals
+fn plain_text(stack: Stack) -> String {
+    let mut data = stack.data;
+    let headings = heading_ids(&mut data);
+    let mut plain = PlainText {
+        references: stack.references,
+        ..PlainText::default()
     };
-    for token in tokens {
-        token.eval(&mut stack)?;
+    let min = headings.iter().map(|(level, _)| *level).min().unwrap_or(1);
+    let toc = (headings.iter()).map(|(level, text)| {
+        let indent = "    ".repeat((level - min) as usize);
+        wrap(
+            &text.plain(),
+            &format!("{indent}- "),
+            &format!("{indent}  "),
+        )
+    });
+    plain.toc = toc.collect::<Vec<_>>().join("\n");
+    let mut body = plain.render(data);
+    if !plain.notes.is_empty() {
+        body += &format!("\n\n{}", "-".repeat(TEXT_WIDTH));
+        for (index, note) in plain.notes.iter().enumerate() {
+            let mark = format!("[{}] ", index + 1);
+            let indent = " ".repeat(mark.chars().count());
+            body += &format!("\n{}", wrap(note, &mark, &indent));
+        }
     }
-    generate(stack)
+    body + "\n"
 }
 
-struct Stack {
-    data: Vec<Value>,
-    scope: HashMap<String, Value>,
-    title: Option<String>,
-    theme: Option<String>,
+/// State shared while rendering plain text, like `Html`
+#[derive(Default)]
+struct PlainText {
+    /// Addresses of the links and contents of the footnotes, referred to by their number
+    notes: Vec<String>,
+    references: Vec<Text>,
+    toc: String,
 }
 
-fn generate(stack: Stack) -> Option<String> {
-    let mut output = Vec::new();
-    let mut list = Vec::new();
-    let mut is_list = false;
-    for value in stack.data {
-        let Value::Text(text) = value else {
-            return None;
-        };
+impl PlainText {
+    fn render(&mut self, values: Vec<Value>) -> String {
+        let mut output = String::new();
+        let mut previous = None;
+        // Numbers of the open ordered lists from the outermost, and rows of the table
+        let mut numbers: Vec<i32> = Vec::new();
+        let mut rows: Vec<(bool, Vec<String>)> = Vec::new();
+        let mut values = values.into_iter().peekable();
+        while let Some(value) = values.next() {
+            let Value::Text(text) = value else {
+                continue;
+            };
+            let mut content = self.inline(&text);
+            for note in &text.footnotes {
+                let note = self.inline(note);
+                content += &self.note(note);
+            }
+            let depth = text.depth;
+            let indent = "    ".repeat(depth);
+            if !matches!(text.tag, HTMLTag::OrderedList(_)) {
+                numbers.clear();
+            }
 
-        macro_rules! set_font_size {
-            ($font_size: expr) => {
-                if let Some(font_size) = $font_size {
-                    format!(" style=\"font-size: {font_size}px;\"")
-                } else {
-                    String::new()
+            let (block, plain) = match text.tag {
+                HTMLTag::Paragraph => (None, wrap(&content, "", "")),
+                HTMLTag::Heading(level) => {
+                    let line = if level <= 1 { '=' } else { '-' };
+                    let width = content.lines().map(|line| line.chars().count()).max();
+                    let underline = line.to_string().repeat(width.unwrap_or(0));
+                    (None, format!("{content}\n{underline}"))
+                }
+                HTMLTag::Link(url) => (None, wrap(&self.link(content, &url), "", "")),
+                HTMLTag::BlockQuote(source) => {
+                    let mut quote = content;
+                    if let Some(source) = source {
+                        let source = self.span(&source);
+                        quote += &format!("\n\u{2014} {source}");
+                    }
+                    let prefix = "> ".repeat(depth + 1);
+                    let lines = wrap(&quote, &prefix, &prefix);
+                    let lines: Vec<&str> = lines.lines().map(str::trim_end).collect();
+                    (Some(Block::Quote), lines.join("\n"))
+                }
+                HTMLTag::CodeBlock(_) | HTMLTag::Diagram | HTMLTag::Math => {
+                    // Triple-quoted code holds plain newlines rather than line breaks
+                    let code = text.content.replace(LINE_BREAK, "\n");
+                    let lines = code.lines().map(|line| format!("    {line}"));
+                    let lines: Vec<String> = lines.collect();
+                    (None, lines.join("\n").trim_end().to_owned())
+                }
+                HTMLTag::Rule => (None, "-".repeat(TEXT_WIDTH)),
+                HTMLTag::Break | HTMLTag::SlideBreak | HTMLTag::RawHtml => continue,
+                HTMLTag::Toc => (None, self.toc.clone()),
+                HTMLTag::Bibliography => {
+                    let references = std::mem::take(&mut self.references);
+                    let items: Vec<String> = (references.iter().enumerate())
+                        .map(|(index, reference)| {
+                            let mark = format!("[{}] ", index + 1);
+                            let indent = " ".repeat(mark.chars().count());
+                            wrap(&self.span(reference), &mark, &indent)
+                        })
+                        .collect();
+                    self.references = references;
+                    (None, items.join("\n"))
+                }
+                HTMLTag::Group { children, .. } => (None, self.render(children)),
+                HTMLTag::Details(summary, children) => {
+                    let summary = wrap(&self.inline(&summary), "", "");
+                    (None, format!("{summary}\n\n{}", self.render(children)))
+                }
+                HTMLTag::Image(url) => (None, self.image(content, &url)),
+                HTMLTag::Figure(image, caption) => {
+                    let HTMLTag::Image(url) = &image.tag else {
+                        continue;
+                    };
+                    let image = self.inline(&image);
+                    let image = self.image(image, url);
+                    let caption = wrap(&self.inline(&caption), "", "");
+                    (None, format!("{image}\n{caption}"))
+                }
+                HTMLTag::Audio(url) | HTMLTag::Video { url, .. } | HTMLTag::Embed(url) => {
+                    (None, wrap(&self.link(content, &url), "", ""))
+                }
+                HTMLTag::List => {
+                    let rest = format!("{indent}  ");
+                    (
+                        Some(Block::List),
+                        wrap(&content, &format!("{indent}- "), &rest),
+                    )
+                }
+                HTMLTag::OrderedList(start) => {
+                    numbers.resize(depth + 1, 0);
+                    let number = start.unwrap_or(numbers[depth] + 1);
+                    numbers[depth] = number;
+                    let mark = format!("{indent}{number}. ");
+                    let rest = " ".repeat(mark.chars().count());
+                    (Some(Block::List), wrap(&content, &mark, &rest))
+                }
+                HTMLTag::Task(done) => {
+                    let mark = if done { 'x' } else { ' ' };
+                    let rest = format!("{indent}    ");
+                    let item = wrap(&content, &format!("{indent}[{mark}] "), &rest);
+                    (Some(Block::List), item)
+                }
+                HTMLTag::Definition(term) => {
+                    let term = wrap(&self.inline(&term), "", "");
+                    (None, format!("{term}\n{}", wrap(&content, "    ", "    ")))
+                }
+                HTMLTag::TableRow { cells, header } => {
+                    let cells = cells.iter().map(|cell| self.inline(cell)).collect();
+                    rows.push((header, cells));
+                    if let Some(Value::Text(next)) = values.peek()
+                        && matches!(next.tag, HTMLTag::TableRow { .. })
+                    {
+                        continue;
+                    }
+                    (None, table(std::mem::take(&mut rows)))
                 }
             };
+            if !output.is_empty() {
+                let separator = match block {
+                    Some(Block::Quote) if depth > 0 && previous == block => {
+                        format!("\n{}\n", "> ".repeat(depth).trim_end())
+                    }
+                    Some(Block::List) if previous == block => "\n".to_owned(),
+                    _ => "\n\n".to_owned(),
+                };
+                output += &separator;
+            }
+            output += &plain;
+            previous = block;
         }
+        output
+    }
 
-        let html = match (text.tag, text.font_size) {
-            (HTMLTag::Paragraph, font_size) => {
-                format!("<p{}>{}</p>", set_font_size!(font_size), text.content)
-            }
-            (HTMLTag::Heading(level), font_size) => {
-                format!(
-                    "<h{level}{}>{}</h{level}>",
-                    set_font_size!(font_size),
-                    text.content,
-                )
-            }
-            (HTMLTag::Link(url), font_size) => format!(
-                "<a href=\"{}\"{}>{}</a>",
-                url,
-                set_font_size!(font_size),
-                text.content,
-            ),
-            (HTMLTag::BlockQuote, font_size) => {
-                format!(
-                    "<blockquote{}>{}</blockquote>",
-                    set_font_size!(font_size),
-                    text.content
-                )
+    /// Content of the text with its appended ones, numbering the links to other pages
+    fn inline(&mut self, text: &Text) -> String {
+        let mut content = text.content.replace(LINE_BREAK, "\n");
+        for span in &text.spans {
+            content += &self.span(span);
+        }
+        (text.inline.iter()).fold(content, |content, inline| match inline {
+            Inline::Abbr(title) => format!("{content} ({title})"),
+            _ => content,
+        })
+    }
+
+    /// Render the appended text inside the content of another one
+    fn span(&mut self, text: &Text) -> String {
+        let content = self.inline(text);
+        match &text.tag {
+            HTMLTag::Link(url) => self.link(content, url),
+            _ => content,
+        }
+    }
+
+    /// Content followed by the number of the address, or the address itself if there's no content
+    fn link(&mut self, content: String, url: &str) -> String {
+        if content.is_empty() {
+            return url.to_owned();
+        }
+        // Anchors in the document, such as citations, lead nowhere outside of it
+        if url.starts_with('#') {
+            return content;
+        }
+        content + &self.note(url.to_owned())
+    }
+
+    /// Image replaced by its description and the number of its address
+    fn image(&mut self, alt: String, url: &str) -> String {
+        let alt = match alt.is_empty() {
+            true => "[Image]".to_owned(),
+            false => format!("[Image: {alt}]"),
+        };
+        match url.starts_with("data:") {
+            true => alt,
+            false => alt + &self.note(url.to_owned()),
+        }
+    }
+
+    /// Reference to the note, numbering it unless it's already listed
+    fn note(&mut self, note: String) -> String {
+        let number = match self.notes.iter().position(|listed| *listed == note) {
+            Some(index) => index + 1,
+            None => {
+                self.notes.push(note);
+                self.notes.len()
             }
-            (HTMLTag::Image(url), _) => {
-                format!("<img src=\"{}\" alt=\"{}\">", url, text.content)
+        };
+        format!(" [{number}]")
+    }
+}
+
+/// Table of the rows with the columns aligned, and a rule under the header ones
+fn table(rows: Vec<(bool, Vec<String>)>) -> String {
+    let columns = rows.iter().map(|(_, cells)| cells.len()).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for (_, cells) in &rows {
+        for (width, cell) in widths.iter_mut().zip(cells) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut lines = Vec::new();
+    for (header, cells) in rows {
+        let cells = (widths.iter().enumerate()).map(|(index, width)| {
+            let cell = cells.get(index).map(String::as_str).unwrap_or_default();
+            format!("{cell}{}", " ".repeat(width - cell.chars().count()))
+        });
+        lines.push(cells.collect::<Vec<_>>().join(" | ").trim_end().to_owned());
+        if header {
+            let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+            lines.push(rule.join("-+-"));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Wrap the words of each line at `TEXT_WIDTH`, prefixing the first line and indenting the others
+fn wrap(text: &str, first: &str, rest: &str) -> String {
+    let mut lines = Vec::new();
+    let mut line = first.to_owned();
+    let mut empty = true;
+    for (index, paragraph) in text.split('\n').enumerate() {
+        if index > 0 {
+            lines.push(std::mem::replace(&mut line, rest.to_owned()));
+            empty = true;
+        }
+        for word in paragraph.split_whitespace() {
+            let width = line.chars().count() + word.chars().count();
+            if !empty && width >= TEXT_WIDTH {
+                lines.push(std::mem::replace(&mut line, rest.to_owned()));
+                empty = true;
             }
-            (HTMLTag::List, font_size) => {
-                list.push(format!(
-                    "<li {}>{}</li>",
-                    set_font_size!(font_size),
-                    text.content
-                ));
-                is_list = true;
-                continue;
+            if !empty {
+                line.push(' ');
             }
-        };
-        if is_list {
-            output.push(format!("<ul>{}</ul>", list.join("\n")));
-            is_list = false;
-            list.clear();
-        }
-        output.push(html);
-    }
-    Some(format!(
-        r#"
-        <html>
-            <head>
-                <meta charset="UTF-8">
-                <title>{}</title>
-                <link rel="stylesheet" href="theme/{}.css">
-            </head>
-            <body>
-                {}
-            </body>
-        </html>
-        "#,
-        stack.title.unwrap_or("Untitled".to_owned()),
-        stack.theme.unwrap_or("none".to_owned()),
-        output.join("\n"),
-    ))
-}
-
-fn tokenize(source: &str) -> Option<Vec<String>> {
-    let mut tokens: Vec<String> = Vec::new();
+            line += word;
+            empty = false;
+        }
+    }
+    lines.push(line);
+    let lines: Vec<&str> = lines.iter().map(|line| line.trim_end()).collect();
+    lines.join("\n")
+}
+
+const TRIPLE_QUOTE: &str = "\"\"\"";
+
+/// Explicit line break in a text, written as `\n` or a backslash at the end of line
+const LINE_BREAK: char = '\u{2028}';
+
+fn tokenize(source: &str, warnings: &mut Vec<Warning>) -> Result<Vec<Token>, Error> {
+    let mut tokens: Vec<Token> = Vec::new();
     let mut current_token = String::new();
     let mut in_quote = false;
+    let mut is_triple = false;
+    let mut is_raw = false;
     let mut is_escape = false;
 
-    for c in source.chars() {
+    let mut span = Span { line: 1, column: 1 };
+    let mut position = 0;
+    let mut start = span.clone();
+
+    macro_rules! fail {
+        ($kind: expr) => {
+            return Err(Error::from($kind).at(&Token {
+                text: current_token,
+                span: start,
+            }))
+        };
+    }
+
+    let mut chars = source.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if current_token.is_empty() {
+            span.advance(&source[position..index]);
+            position = index;
+            start = span.clone();
+        }
         if is_escape {
             current_token.push(match c {
                 'n' => '\n',
                 't' => '\t',
                 'r' => '\r',
-                _ => c,
+                'u' => {
+                    let Some((_, '{')) = chars.next() else {
+                        fail!(ErrorKind::InvalidEscape);
+                    };
+                    let mut code = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => code.push(c),
+                            None => fail!(ErrorKind::InvalidEscape),
+                        }
+                    }
+                    let code = u32::from_str_radix(&code, 16).ok();
+                    let Some(c) = code.and_then(char::from_u32) else {
+                        fail!(ErrorKind::InvalidEscape);
+                    };
+                    c
+                }
+                '"' | '\\' | '\n' | ' ' => c,
+                _ => {
+                    warnings.push(Warning {
+                        kind: WarningKind::UnknownEscape(c),
+                        token: Some(Token {
+                            text: current_token.clone(),
+                            span: start.clone(),
+                        }),
+                        path: None,
+                    });
+                    c
+                }
             });
             is_escape = false;
         } else {
             match c {
+                '"' if is_triple && source[index..].starts_with(TRIPLE_QUOTE) => {
+                    chars.nth(1);
+                    current_token.push_str(TRIPLE_QUOTE);
+                    in_quote = false;
+                    is_triple = false;
+                    is_raw = false;
+                }
+                '"' if is_triple => current_token.push(c),
+                '"' if !in_quote && source[index..].starts_with(TRIPLE_QUOTE) => {
+                    chars.nth(1);
+                    is_raw = current_token == "r";
+                    current_token.push_str(TRIPLE_QUOTE);
+                    in_quote = true;
+                    is_triple = true;
+                }
                 '"' => {
                     in_quote = !in_quote;
+                    is_raw = in_quote && current_token == "r";
                     current_token.push(c);
                 }
-                '\\' if in_quote => {
+                '\\' if in_quote && !is_raw => {
                     current_token.push(c);
                     is_escape = true;
                 }
-                ' ' | '\n' | '\t' | '\r' if !in_quote && !current_token.is_empty() => {
-                    tokens.push(current_token.clone());
-                    current_token.clear();
+                ' ' | '\n' | '\t' | '\r' if !in_quote => {
+                    if !current_token.is_empty() {
+                        tokens.push(Token {
+                            text: current_token.clone(),
+                            span: start.clone(),
+                        });
+                        current_token.clear();
+                    }
                 }
                 _ => current_token.push(c),
             }
@@ -180,12 +4679,26 @@ fn tokenize(source: &str) -> Option<Vec<String>> {
     }
 
     if is_escape || in_quote {
-        return None;
+        fail!(ErrorKind::UnterminatedString);
     }
     if !current_token.is_empty() {
-        tokens.push(current_token.clone());
+        tokens.push(Token {
+            text: current_token,
+            span: start,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Trim the whitespace around the text, keeping a trailing one escaped like `\ `
+fn trim_unescaped(text: &str) -> &str {
+    let start = text.len() - text.trim_start().len();
+    let mut end = text.trim_end().len().max(start);
+    let backslashes = text[start..end].chars().rev().take_while(|&c| c == '\\');
+    if backslashes.count() % 2 == 1 {
+        end += text[end..].chars().next().map_or(0, char::len_utf8);
     }
-    Some(tokens)
+    &text[start..end]
 }
 
 fn text_escape(text: &str) -> String {
@@ -207,233 +4720,1503 @@ fn text_escape(text: &str) -> String {
     result
 }
 
+fn format(template: &str, stack: &mut Stack) -> Result<String, Error> {
+    let (literals, placeholders) = parse_format(template)?;
+    let count = placeholders.iter().filter(|x| x.is_empty()).count();
+    let Some(index) = stack.data.len().checked_sub(count) else {
+        return Err(ErrorKind::StackUnderflow.into());
+    };
+    let mut args = stack.data.split_off(index).into_iter();
+    let mut result = literals[0].clone();
+    for (name, literal) in placeholders.iter().zip(&literals[1..]) {
+        if name.is_empty() {
+            result.extend(args.next().map(|arg| arg.to_string()));
+        } else {
+            result.push_str(&stack.lookup(name)?.to_string());
+        }
+        result.push_str(literal);
+    }
+    Ok(result)
+}
+
+/// Split a format text into its literal parts and the placeholders between them
+fn parse_format(template: &str) -> Result<(Vec<String>, Vec<String>), ErrorKind> {
+    let mut literal = String::new();
+    let mut literals = Vec::new();
+    let mut placeholders: Vec<String> = Vec::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(ErrorKind::InvalidFormat("unclosed `{`")),
+                    }
+                }
+                placeholders.push(name.trim().to_string());
+                literals.push(std::mem::take(&mut literal));
+            }
+            '}' => return Err(ErrorKind::InvalidFormat("unmatched `}`")),
+            _ => literal.push(c),
+        }
+    }
+    literals.push(literal);
+    Ok((literals, placeholders))
+}
+
+/// Replace `{@name}` in the text with the variable's value,
+/// where `{{@` and `@@` are escapes of literal `{@` and `@`
+fn interpolate(content: &str, stack: &mut Stack) -> String {
+    let mut result = String::new();
+    let mut rest = content;
+    while let Some(index) = rest.find(['{', '@']) {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("{{@") {
+            result.push_str("{@");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("@@") {
+            result.push('@');
+            rest = after;
+        } else if let Some((name, after)) = (rest.strip_prefix("{@"))
+            .and_then(|x| x.split_once('}'))
+            .filter(|(name, _)| !name.is_empty() && name.chars().all(is_name_char))
+        {
+            match stack.lookup(name) {
+                Ok(value) => result.push_str(&value.to_string()),
+                Err(_) => {
+                    stack.warn(WarningKind::Undefined(name.to_string()));
+                    result.push_str(&rest[..rest.len() - after.len()]);
+                }
+            }
+            rest = after;
+        } else {
+            result.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn unquote(source: &str) -> Option<&str> {
+    (source.strip_prefix(TRIPLE_QUOTE))
+        .and_then(|x| x.strip_suffix(TRIPLE_QUOTE))
+        .or_else(|| source.strip_prefix("\"").and_then(|x| x.strip_suffix("\"")))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
 #[derive(Clone, Debug)]
 enum Value {
     Text(Text),
     Integer(i32),
+    Bool(bool),
     Link(String),
     Symbol(String),
+    Quote(Vec<Expr>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
 }
 
 impl Value {
-    fn eval(&self, stack: &mut Stack) -> Option<()> {
+    fn eval(&self, stack: &mut Stack) -> Result<(), Error> {
         match self {
-            Value::Text(text) => stack.data.push(Value::Text(Text {
-                content: tokenize(&text.content)?
-                    .iter()
-                    .map(|token| {
-                        if let Some(name) = token.strip_prefix("@") {
-                            stack.scope.get(name).map(|x| x.to_string())
-                        } else {
-                            Some(token.clone())
-                        }
-                    })
-                    .collect::<Option<Vec<String>>>()?
-                    .join(" "),
-                ..text.clone()
-            })),
+            Value::Text(text) => {
+                let content = interpolate(&text.content, stack);
+                stack.data.push(Value::Text(Text {
+                    content,
+                    ..text.clone()
+                }));
+            }
+            Value::Link(url) if url.starts_with("http://") => {
+                stack.warn(WarningKind::InsecureLink(url.clone()));
+                stack.data.push(self.clone());
+            }
             _ => stack.data.push(self.clone()),
         }
-        Some(())
+        Ok(())
     }
 
-    fn to_string(&self) -> String {
+    fn type_name(&self) -> &'static str {
+        self.ty().name()
+    }
+
+    fn ty(&self) -> Type {
         match self {
-            Value::Integer(int) => int.to_string(),
-            Value::Text(text) => text.content.clone(),
-            Value::Link(text) | Value::Symbol(text) => text.clone(),
+            Value::Text(_) => Type::Text,
+            Value::Integer(_) => Type::Integer,
+            Value::Bool(_) => Type::Bool,
+            Value::Link(_) => Type::Link,
+            Value::Symbol(_) => Type::Symbol,
+            Value::Quote(_) => Type::Quote,
+            Value::List(_) => Type::List,
+            Value::Map(_) => Type::Map,
         }
     }
 
     fn parse(source: &str) -> Option<Value> {
-        if let Some(text) = source.strip_prefix("\"").and_then(|x| x.strip_suffix("\"")) {
-            Some(Value::Text(Text {
-                content: text_escape(text.replace("\\\n", "<br>").trim()),
-                font_size: None,
-                tag: HTMLTag::Paragraph,
-            }))
-        } else if let Some(number) = source.parse::<i32>().ok() {
+        if let Some(text) = unquote(source) {
+            let text = text.replace("\\\n", &LINE_BREAK.to_string());
+            let content = text_escape(trim_unescaped(&text));
+            Some(Value::Text(Text::new(content)))
+        } else if let Ok(number) = source.parse::<i32>() {
             Some(Value::Integer(number))
-        } else if source.starts_with("https://") {
+        } else if let Ok(bool) = source.parse::<bool>() {
+            Some(Value::Bool(bool))
+        } else if source.starts_with("https://")
+            || source.starts_with("http://")
+            || (source.strip_prefix("mailto:")).is_some_and(|to| to.contains('@'))
+        {
+            Some(Value::Link(source.to_string()))
+        } else if let Some(id) = source.strip_prefix('#')
+            && !id.is_empty()
+            && id.chars().all(is_name_char)
+        {
             Some(Value::Link(source.to_string()))
-        } else if let Some(name) = source.strip_prefix("@") {
-            Some(Value::Symbol(name.to_string()))
         } else {
-            None
+            source
+                .strip_prefix("@")
+                .map(|name| Value::Symbol(name.to_string()))
         }
     }
 }
 
-#[derive(Clone, Debug)]
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Integer(int) => write!(f, "{int}"),
+            Value::Bool(bool) => write!(f, "{bool}"),
+            Value::Text(text) => write!(f, "{}", text.plain()),
+            Value::Link(text) | Value::Symbol(text) => write!(f, "{text}"),
+            Value::Quote(_) => write!(f, "[...]"),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(Value::to_string).collect();
+                write!(f, "{}", items.join(", "))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<String> = (entries.iter())
+                    .map(|(key, value)| format!("{key}: {value}"))
+                    .collect();
+                write!(f, "{}", entries.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 struct Text {
+    #[serde(serialize_with = "serialize_content")]
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     font_size: Option<i32>,
     tag: HTMLTag,
+    /// Inline elements wrapping the content, from the innermost
+    inline: Vec<Inline>,
+    /// Nesting depth of a list item, where 0 is the top level
+    depth: usize,
+    /// Notes referenced at the end of the content
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    footnotes: Vec<Text>,
+    /// Texts appended after the content by `append`, each keeping its own styling
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    spans: Vec<Text>,
+    attributes: Box<Attributes>,
 }
 
-#[derive(Clone, Debug)]
+/// Content with the explicit line breaks as newlines
+fn serialize_content<S: Serializer>(content: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&content.replace(LINE_BREAK, "\n"))
+}
+
+/// Only the texts among the values, as the others aren't rendered
+fn serialize_texts<S: Serializer>(values: &[Value], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().filter_map(|value| match value {
+        Value::Text(text) => Some(text),
+        _ => None,
+    }))
+}
+
+/// HTML attributes set by commands, boxed as most texts have none
+#[derive(Clone, Debug, Default, Serialize)]
+struct Attributes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    classes: Vec<String>,
+    /// CSS declarations like `color: red` after the font size
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    styles: Vec<String>,
+    /// Other HTML attributes as name and value
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attrs: Vec<(String, String)>,
+}
+
+impl Attributes {
+    /// Set the other attribute, replacing the previous value
+    fn set(&mut self, name: &str, value: String) {
+        match self.attrs.iter_mut().find(|(key, _)| key == name) {
+            Some((_, old)) => *old = value,
+            None => self.attrs.push((name.to_owned(), value)),
+        }
+    }
+}
+
+/// Whether the text is a relative URL or an absolute one with a safe scheme
+fn valid_url(url: &str) -> bool {
+    if url.is_empty() || url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+    let end = url.find(['/', '?', '#']).unwrap_or(url.len());
+    match url[..end].split_once(':') {
+        Some((scheme, _)) => ["http", "https", "mailto", "tel"].contains(&scheme),
+        None => true,
+    }
+}
+
+/// Host part of the absolute URL
+fn host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    rest.split(['/', '?', '#']).next()
+}
+
+/// Open the link in a new tab, returning whether it's one
+fn new_tab(text: &mut Text) -> bool {
+    if !matches!(text.tag, HTMLTag::Link(_)) {
+        return false;
+    }
+    text.attributes.set("target", "_blank".to_owned());
+    add_rel(text, "noopener noreferrer");
+    true
+}
+
+/// Add the link types to the `rel` attribute, keeping the existing ones
+fn add_rel(text: &mut Text, rel: &str) {
+    let attributes = &mut text.attributes;
+    let mut types: Vec<&str> = Vec::new();
+    let old = attributes.attrs.iter().find(|(name, _)| name == "rel");
+    let old = old.map(|(_, value)| value.clone()).unwrap_or_default();
+    for kind in old.split_whitespace().chain(rel.split_whitespace()) {
+        if !types.contains(&kind) {
+            types.push(kind);
+        }
+    }
+    attributes.set("rel", types.join(" "));
+}
+
+/// Image file embedded in a `data:` URL
+fn data_url(path: &Path) -> Result<String, Error> {
+    let Some(mime) = image_type(path) else {
+        let path = path.display().to_string();
+        return Err(ErrorKind::InvalidValue("image file", path).into());
+    };
+    let bytes = std::fs::read(path).map_err(|e| ErrorKind::Io(path.to_path_buf(), e))?;
+    Ok(format!("data:{mime};base64,{}", STANDARD.encode(bytes)))
+}
+
+/// MIME type of the image file guessed from its extension
+fn image_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        _ => return None,
+    })
+}
+
+/// Defer loading the image until it's scrolled near, returning whether it's one
+fn lazy(text: &mut Text) -> bool {
+    match &mut text.tag {
+        HTMLTag::Image(_) => {
+            let attributes = &mut text.attributes;
+            if !attributes.attrs.iter().any(|(name, _)| name == "loading") {
+                attributes.set("loading", "lazy".to_owned());
+                attributes.set("decoding", "async".to_owned());
+            }
+            true
+        }
+        HTMLTag::Figure(image, _) => lazy(image),
+        _ => false,
+    }
+}
+
+impl Text {
+    fn new(content: String) -> Text {
+        Text {
+            content,
+            font_size: None,
+            tag: HTMLTag::Paragraph,
+            inline: Vec::new(),
+            depth: 0,
+            footnotes: Vec::new(),
+            spans: Vec::new(),
+            attributes: Box::default(),
+        }
+    }
+
+    /// Content followed by the appended spans, without any markup
+    fn plain(&self) -> String {
+        let spans = self.spans.iter().map(Text::plain);
+        std::iter::once(self.content.clone()).chain(spans).collect()
+    }
+
+    /// HTML attributes of the element, each with a leading space
+    fn html_attributes(&self) -> String {
+        let Attributes {
+            id,
+            classes,
+            styles,
+            attrs,
+        } = &*self.attributes;
+        let mut attributes = String::new();
+        if let Some(id) = id {
+            attributes += &format!(" id=\"{}\"", html_escape(id));
+        }
+        if !classes.is_empty() {
+            attributes += &format!(" class=\"{}\"", html_escape(&classes.join(" ")));
+        }
+        let font_size = (self.font_size).map(|font_size| format!("font-size: {font_size}px"));
+        let styles: Vec<String> = font_size.into_iter().chain(styles.clone()).collect();
+        if !styles.is_empty() {
+            attributes += &format!(" style=\"{};\"", html_escape(&styles.join("; ")));
+        }
+        for (name, value) in attrs {
+            attributes += &format!(" {name}=\"{}\"", html_escape(value));
+        }
+        attributes
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Inline {
+    Bold,
+    Italic,
+    Strike,
+    Code,
+    Sup,
+    Sub,
+    Kbd,
+    Mark,
+    Small,
+    /// LaTeX source typeset by KaTeX in the browser
+    Math,
+    /// Abbreviation with its full form shown as a tooltip
+    Abbr(String),
+}
+
+impl Inline {
+    /// Opening tag without the angle brackets
+    fn tag(&self) -> String {
+        match self {
+            Inline::Bold => "strong".to_owned(),
+            Inline::Italic => "em".to_owned(),
+            Inline::Strike => "s".to_owned(),
+            Inline::Code => "code".to_owned(),
+            Inline::Sup => "sup".to_owned(),
+            Inline::Sub => "sub".to_owned(),
+            Inline::Kbd => "kbd".to_owned(),
+            Inline::Mark => "mark".to_owned(),
+            Inline::Small => "small".to_owned(),
+            Inline::Math => "span class=\"math\"".to_owned(),
+            Inline::Abbr(title) => format!("abbr title=\"{}\"", html_escape(title)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
 enum HTMLTag {
     Heading(i32),
     Paragraph,
     Link(String),
     Image(String),
-    BlockQuote,
+    /// Audio player, whose content is shown if the browser can't play it
+    Audio(String),
+    /// Page of another site in a sandboxed frame
+    Embed(String),
+    Video {
+        url: String,
+        /// Image shown until the video plays
+        poster: Option<String>,
+    },
+    /// Image with its caption
+    Figure(Box<Text>, Box<Text>),
+    CodeBlock(String),
+    /// LaTeX source typeset as a display formula
+    Math,
+    /// Mermaid source drawn in the browser
+    Diagram,
+    /// Markup emitted as is, without escaping
+    RawHtml,
+    Rule,
+    Break,
+    /// Boundary between slides, which only the `--slides` mode shows
+    SlideBreak,
+    /// Placeholder for the table of contents
+    Toc,
+    /// Placeholder for the list of the cited works
+    Bibliography,
+    /// Element wrapping the values left by a quotation
+    Group {
+        element: &'static str,
+        #[serde(serialize_with = "serialize_texts")]
+        children: Vec<Value>,
+    },
+    /// Collapsible values behind a summary
+    Details(
+        Box<Text>,
+        #[serde(serialize_with = "serialize_texts")] Vec<Value>,
+    ),
+    /// Quotation, optionally attributed to a source
+    BlockQuote(Option<Box<Text>>),
     List,
+    OrderedList(Option<i32>),
+    Task(bool),
+    Definition(Box<Text>),
+    TableRow {
+        cells: Vec<Text>,
+        header: bool,
+    },
 }
 
 #[derive(Clone, Debug)]
 enum Node {
     Literal(Value),
     Command(Command),
+    Word(String),
+    Raw(Text),
+    /// List literal, whose elements are the values its code pushes
+    List(Vec<Expr>),
 }
 
 impl Node {
-    fn eval(&self, stack: &mut Stack) -> Option<()> {
+    fn eval(&self, stack: &mut Stack) -> Result<(), Error> {
         match self {
             Node::Literal(value) => value.eval(stack)?,
             Node::Command(command) => command.eval(stack)?,
+            Node::Word(name) => match stack.lookup(name)?.clone() {
+                Value::Quote(body) => stack.call(&body)?,
+                value => stack.data.push(value),
+            },
+            Node::Raw(text) => stack.data.push(Value::Text(text.clone())),
+            Node::List(body) => {
+                let items = stack.collect(body)?;
+                stack.data.push(Value::List(items));
+            }
         }
-        Some(())
+        Ok(())
     }
 
     fn parse(source: &str) -> Option<Node> {
         if let Some(value) = Command::parse(source) {
             Some(Node::Command(value))
+        } else if let Some(text) = source.strip_prefix("r").and_then(unquote) {
+            Some(Node::Raw(Text::new(text.to_string())))
         } else if let Some(value) = Value::parse(source) {
             Some(Node::Literal(value))
+        } else if source.chars().all(is_name_char) {
+            Some(Node::Word(source.to_string()))
         } else {
             None
         }
     }
 }
 
+/// Node together with the token it was parsed from
+#[derive(Clone, Debug)]
+struct Expr {
+    node: Node,
+    token: Token,
+}
+
+impl Expr {
+    fn eval(&self, stack: &mut Stack) -> Result<(), Error> {
+        let count = stack.warnings.len();
+        let result = self.node.eval(stack).map_err(|error| error.at(&self.token));
+        for warning in &mut stack.warnings[count..] {
+            warning.token.get_or_insert_with(|| self.token.clone());
+        }
+        result
+    }
+}
+
 #[derive(Clone, Debug)]
 enum Command {
     Heading,
     FontSize,
     Link,
     BlockQuote,
+    QuoteSource,
     Image,
     List,
+    Bold,
+    Italic,
+    Strike,
+    Code,
+    Sup,
+    Sub,
+    Kbd,
+    Mark,
+    Small,
+    Abbr,
+    Math,
+    MathBlock,
+    Diagram,
+    RawHtml,
+    CodeBlock,
+    Figure,
+    Audio,
+    Video,
+    Embed,
+    Poster,
+    Size,
+    ImageInline,
+    Url,
+    NewTab,
+    Rel,
+    Width,
+    Height,
+    Lazy,
+    Fallback,
+    Hr,
+    Br,
+    SlideBreak,
+    Toc,
+    Cite,
+    Bibliography,
+    Section,
+    Div,
+    PageHeader,
+    PageFooter,
+    Nav,
+    Columns,
+    Details,
+    Footnote,
+    Class,
+    Id,
+    Attr,
+    Style,
+    Color,
+    BgColor,
+    Align,
+    OrderedList,
+    ListStart,
+    ListDepth,
+    Task,
+    DefList,
+    TableRow,
+    TableHeader,
     Title,
     Theme,
+    Description,
+    Author,
+    Keywords,
+    Published,
+    Canonical,
+    TwitterCard,
+    Favicon,
+    Lang,
+    Include,
+    ReadFile,
+    Env,
+    Now,
+    Today,
+    DateFormat,
     Load,
     Store,
+    Const,
+    Defined,
+    Assert,
+    Error,
     Concat,
+    Append,
+    Format,
+    Len,
+    Slice,
+    Split,
+    Join,
+    Nth,
+    Push,
+    PopItem,
+    Count,
+    MapNew,
+    MapSet,
+    MapGet,
     Dup,
     Swap,
     Pop,
+    Call,
+    Each,
+    Map,
+    Scope,
+    Rot,
+    Over,
+    Nip,
+    Tuck,
+    Depth,
+    Clear,
 }
 
 impl Command {
-    fn eval(&self, stack: &mut Stack) -> Option<()> {
+    fn eval(&self, stack: &mut Stack) -> Result<(), Error> {
         match self {
             Command::Heading => {
-                let Value::Integer(level) = stack.data.pop()? else {
-                    return None;
+                let level = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                if !(1..=6).contains(&level) {
+                    stack.warn(WarningKind::HeadingLevel(level));
+                }
+                text.tag = HTMLTag::Heading(level);
+                stack.data.push(Value::Text(text));
+            }
+            Command::FontSize => {
+                let size = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                text.font_size = Some(size);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Link => {
+                let url = pop!(stack, Link);
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::Link(url);
+                stack.data.push(Value::Text(text));
+            }
+            Command::NewTab => {
+                let mut text = pop!(stack, Text);
+                if !new_tab(&mut text) {
+                    return Err(ErrorKind::TypeMismatch {
+                        expected: "Link",
+                        found: "Text",
+                    }
+                    .into());
+                }
+                stack.data.push(Value::Text(text));
+            }
+            Command::Rel => {
+                let rel = pop!(stack, Text).content;
+                let mut text = pop!(stack, Text);
+                if !matches!(text.tag, HTMLTag::Link(_)) {
+                    return Err(ErrorKind::TypeMismatch {
+                        expected: "Link",
+                        found: "Text",
+                    }
+                    .into());
+                }
+                let valid =
+                    |kind: &str| kind.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+                if rel.split_whitespace().next().is_none() || !rel.split_whitespace().all(valid) {
+                    return Err(ErrorKind::InvalidValue("link type", rel).into());
+                }
+                add_rel(&mut text, &rel);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Url => {
+                let url = pop!(stack, Text).content;
+                if !valid_url(&url) {
+                    return Err(ErrorKind::InvalidValue("URL", url).into());
+                }
+                if url.starts_with("http://") {
+                    stack.warn(WarningKind::InsecureLink(url.clone()));
+                }
+                stack.data.push(Value::Link(url));
+            }
+            Command::BlockQuote => {
+                let mut text = pop!(stack, Text);
+                // Quoting a quote nests it a level deeper
+                match text.tag {
+                    HTMLTag::BlockQuote(_) => text.depth += 1,
+                    _ => text.depth = 0,
+                }
+                text.tag = HTMLTag::BlockQuote(None);
+                stack.data.push(Value::Text(text));
+            }
+            Command::QuoteSource => {
+                let source = match pop!(stack) {
+                    Value::Text(text) => text,
+                    Value::Link(url) => Text {
+                        tag: HTMLTag::Link(url.clone()),
+                        ..Text::new(url)
+                    },
+                    value => {
+                        return Err(ErrorKind::TypeMismatch {
+                            expected: "Text",
+                            found: value.type_name(),
+                        }
+                        .into());
+                    }
+                };
+                let mut text = pop!(stack, Text);
+                if !matches!(text.tag, HTMLTag::BlockQuote(_)) {
+                    text.depth = 0;
+                }
+                text.tag = HTMLTag::BlockQuote(Some(Box::new(source)));
+                stack.data.push(Value::Text(text));
+            }
+            Command::Image | Command::Audio | Command::Video | Command::Embed => {
+                let url = match pop!(stack) {
+                    Value::Link(url) => url,
+                    Value::Text(path) if matches!(self, Command::Image) => {
+                        stack.asset(&path.content)?
+                    }
+                    value => {
+                        return Err(ErrorKind::TypeMismatch {
+                            expected: "Link",
+                            found: value.type_name(),
+                        }
+                        .into());
+                    }
+                };
+                let tag = match self {
+                    Command::Embed => HTMLTag::Embed(url),
+                    Command::Audio => HTMLTag::Audio(url),
+                    Command::Video => HTMLTag::Video { url, poster: None },
+                    _ => HTMLTag::Image(url),
+                };
+                let text = Text {
+                    tag,
+                    ..Text::new(String::new())
+                };
+                stack.data.push(Value::Text(text));
+            }
+            Command::ImageInline => {
+                let url = data_url(&stack.dir.join(pop!(stack, Text).content))?;
+                let text = Text {
+                    tag: HTMLTag::Image(url),
+                    ..Text::new(String::new())
+                };
+                stack.data.push(Value::Text(text));
+            }
+            Command::Poster => {
+                let image = pop!(stack, Link);
+                let mut text = pop!(stack, Text);
+                let HTMLTag::Video { poster, .. } = &mut text.tag else {
+                    return Err(ErrorKind::TypeMismatch {
+                        expected: "Video",
+                        found: "Text",
+                    }
+                    .into());
                 };
-                let Value::Text(mut text) = stack.data.pop()? else {
-                    return None;
+                *poster = Some(image);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Size => {
+                let height = pop!(stack, Integer);
+                let width = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                if width <= 0 || height <= 0 {
+                    return Err(ErrorKind::InvalidValue("size", format!("{width}x{height}")).into());
+                }
+                text.attributes.set("width", width.to_string());
+                text.attributes.set("height", height.to_string());
+                stack.data.push(Value::Text(text));
+            }
+            Command::Fallback => {
+                let fallback = pop!(stack, Text);
+                let text = pop!(stack, Text);
+                let text = Text {
+                    content: fallback.content,
+                    inline: fallback.inline,
+                    ..text
                 };
-                text.tag = HTMLTag::Heading(level);
                 stack.data.push(Value::Text(text));
             }
-            Command::FontSize => {
-                let Value::Integer(size) = stack.data.pop()? else {
-                    return None;
+            Command::Class => {
+                let class = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                (text.attributes.classes)
+                    .extend(class.content.split_whitespace().map(str::to_owned));
+                stack.data.push(Value::Text(text));
+            }
+            Command::Id => {
+                let id = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                text.attributes.id = Some(id.content);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Style => {
+                let style = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                let declarations = style.content.split(';').map(str::trim);
+                (text.attributes.styles).extend(
+                    declarations
+                        .filter(|style| !style.is_empty())
+                        .map(str::to_owned),
+                );
+                stack.data.push(Value::Text(text));
+            }
+            Command::Color | Command::BgColor => {
+                let color = pop!(stack, Text).content;
+                let mut text = pop!(stack, Text);
+                let valid = match color.strip_prefix('#') {
+                    Some(hex) => {
+                        [3, 4, 6, 8].contains(&hex.len())
+                            && hex.chars().all(|c| c.is_ascii_hexdigit())
+                    }
+                    None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic()),
                 };
-                let Value::Text(mut text) = stack.data.pop()? else {
-                    return None;
+                if !valid {
+                    return Err(ErrorKind::InvalidValue("color", color).into());
+                }
+                let property = match self {
+                    Command::Color => "color",
+                    _ => "background-color",
                 };
-                text.font_size = Some(size);
+                text.attributes.styles.push(format!("{property}: {color}"));
                 stack.data.push(Value::Text(text));
             }
-            Command::Link => {
-                let Value::Link(url) = stack.data.pop()? else {
-                    return None;
+            Command::Align => {
+                let align = pop!(stack, Text).content;
+                let mut text = pop!(stack, Text);
+                if !["left", "right", "center", "justify", "start", "end"].contains(&align.as_str())
+                {
+                    return Err(ErrorKind::InvalidValue("alignment", align).into());
+                }
+                text.attributes.styles.push(format!("text-align: {align}"));
+                stack.data.push(Value::Text(text));
+            }
+            Command::Attr => {
+                let name = pop!(stack, Text).content;
+                let value = pop!(stack, Text).content;
+                let mut text = pop!(stack, Text);
+                let invalid = |c: char| c.is_whitespace() || "\"'<>/=".contains(c);
+                if name.is_empty() || name.contains(invalid) {
+                    return Err(ErrorKind::InvalidAttribute(name).into());
+                }
+                text.attributes.set(&name, value);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Footnote => {
+                let note = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                text.footnotes.push(note);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Cite => {
+                let reference = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                let key = reference.plain();
+                let references = &mut stack.references;
+                let number = match references.iter().position(|other| other.plain() == key) {
+                    Some(index) => index + 1,
+                    None => {
+                        references.push(reference);
+                        references.len()
+                    }
                 };
-                let Value::Text(mut text) = stack.data.pop()? else {
-                    return None;
+                text.spans.push(Text {
+                    tag: HTMLTag::Link(format!("#ref-{number}")),
+                    attributes: Box::new(Attributes {
+                        classes: vec!["citation".to_owned()],
+                        ..Attributes::default()
+                    }),
+                    ..Text::new(format!("[{number}]"))
+                });
+                stack.data.push(Value::Text(text));
+            }
+            Command::Details => {
+                let summary = pop!(stack, Text);
+                let body = pop!(stack, Quote);
+                let children = stack.collect(&body)?;
+                let text = Text {
+                    tag: HTMLTag::Details(Box::new(summary), children),
+                    ..Text::new(String::new())
                 };
-                text.tag = HTMLTag::Link(url);
                 stack.data.push(Value::Text(text));
             }
-            Command::BlockQuote => {
-                let Value::Text(mut text) = stack.data.pop()? else {
-                    return None;
+            Command::Figure => {
+                let caption = pop!(stack, Text);
+                let mut image = pop!(stack, Text);
+                if !matches!(image.tag, HTMLTag::Image(_)) {
+                    return Err(ErrorKind::TypeMismatch {
+                        expected: "Image",
+                        found: "Text",
+                    }
+                    .into());
+                }
+                if image.content.is_empty() {
+                    image.content = caption.plain();
+                }
+                let text = Text {
+                    tag: HTMLTag::Figure(Box::new(image), Box::new(caption)),
+                    ..Text::new(String::new())
+                };
+                stack.data.push(Value::Text(text));
+            }
+            Command::Lazy => {
+                let mut text = pop!(stack, Text);
+                if !lazy(&mut text) {
+                    return Err(ErrorKind::TypeMismatch {
+                        expected: "Image",
+                        found: "Text",
+                    }
+                    .into());
+                }
+                stack.data.push(Value::Text(text));
+            }
+            Command::Width | Command::Height => {
+                let length = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                let name = match self {
+                    Command::Width => "width",
+                    _ => "height",
                 };
-                text.tag = HTMLTag::BlockQuote;
+                if length <= 0 {
+                    return Err(ErrorKind::InvalidValue(name, length.to_string()).into());
+                }
+                text.attributes.set(name, length.to_string());
                 stack.data.push(Value::Text(text));
             }
-            Command::Image => {
-                let Value::Link(url) = stack.data.pop()? else {
-                    return None;
+            Command::Hr
+            | Command::Br
+            | Command::SlideBreak
+            | Command::Toc
+            | Command::Bibliography => {
+                let tag = match self {
+                    Command::Hr => HTMLTag::Rule,
+                    Command::SlideBreak => HTMLTag::SlideBreak,
+                    Command::Toc => HTMLTag::Toc,
+                    Command::Bibliography => HTMLTag::Bibliography,
+                    _ => HTMLTag::Break,
                 };
                 let text = Text {
-                    content: String::new(),
-                    font_size: None,
-                    tag: HTMLTag::Image(url),
+                    tag,
+                    ..Text::new(String::new())
                 };
                 stack.data.push(Value::Text(text));
             }
             Command::List => {
-                let Value::Text(mut text) = stack.data.pop()? else {
-                    return None;
-                };
+                let mut text = pop!(stack, Text);
                 text.tag = HTMLTag::List;
                 stack.data.push(Value::Text(text));
             }
-            Command::Title => {
-                let Value::Text(text) = stack.data.pop()? else {
-                    return None;
+            Command::OrderedList => {
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::OrderedList(None);
+                stack.data.push(Value::Text(text));
+            }
+            Command::ListDepth => {
+                let depth = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                text.depth =
+                    usize::try_from(depth - 1).map_err(|_| ErrorKind::InvalidDepth(depth))?;
+                if !matches!(
+                    text.tag,
+                    HTMLTag::List | HTMLTag::OrderedList(_) | HTMLTag::Task(_)
+                ) {
+                    text.tag = HTMLTag::List;
+                }
+                stack.data.push(Value::Text(text));
+            }
+            Command::ListStart => {
+                let start = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::OrderedList(Some(start));
+                stack.data.push(Value::Text(text));
+            }
+            Command::Task => {
+                let mut text = pop!(stack, Text);
+                let done = pop!(stack, Bool);
+                text.tag = HTMLTag::Task(done);
+                stack.data.push(Value::Text(text));
+            }
+            Command::DefList => {
+                let mut text = pop!(stack, Text);
+                let term = pop!(stack, Text);
+                text.tag = HTMLTag::Definition(Box::new(term));
+                stack.data.push(Value::Text(text));
+            }
+            Command::TableRow | Command::TableHeader => {
+                let cells = pop!(stack, List);
+                let cells = (cells.into_iter())
+                    .map(|cell| match cell {
+                        Value::Text(text) => text,
+                        value => Text::new(value.to_string()),
+                    })
+                    .collect();
+                let header = matches!(self, Command::TableHeader);
+                let text = Text {
+                    tag: HTMLTag::TableRow { cells, header },
+                    ..Text::new(String::new())
                 };
+                stack.data.push(Value::Text(text));
+            }
+            Command::Bold
+            | Command::Italic
+            | Command::Strike
+            | Command::Code
+            | Command::Sup
+            | Command::Sub
+            | Command::Kbd
+            | Command::Mark
+            | Command::Small
+            | Command::Math => {
+                let mut text = pop!(stack, Text);
+                text.inline.push(match self {
+                    Command::Bold => Inline::Bold,
+                    Command::Italic => Inline::Italic,
+                    Command::Strike => Inline::Strike,
+                    Command::Sup => Inline::Sup,
+                    Command::Sub => Inline::Sub,
+                    Command::Kbd => Inline::Kbd,
+                    Command::Mark => Inline::Mark,
+                    Command::Small => Inline::Small,
+                    Command::Math => Inline::Math,
+                    _ => Inline::Code,
+                });
+                stack.data.push(Value::Text(text));
+            }
+            Command::Abbr => {
+                let title = pop!(stack, Text).content;
+                let mut text = pop!(stack, Text);
+                text.inline.push(Inline::Abbr(title));
+                stack.data.push(Value::Text(text));
+            }
+            Command::MathBlock => {
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::Math;
+                let classes = &mut text.attributes.classes;
+                classes.splice(0..0, ["math".to_owned(), "display".to_owned()]);
+                stack.data.push(Value::Text(text));
+            }
+            Command::RawHtml => {
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::RawHtml;
+                stack.data.push(Value::Text(text));
+            }
+            Command::Diagram => {
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::Diagram;
+                text.attributes.classes.insert(0, "mermaid".to_owned());
+                stack.data.push(Value::Text(text));
+            }
+            Command::CodeBlock => {
+                let language = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                text.tag = HTMLTag::CodeBlock(language.content);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Title => {
+                let text = pop!(stack, Text);
                 stack.title = Some(text.content);
             }
             Command::Theme => {
-                let Value::Text(text) = stack.data.pop()? else {
-                    return None;
-                };
+                let text = pop!(stack, Text);
                 stack.theme = Some(text.content);
             }
-            Command::Load => {
-                let Value::Symbol(name) = stack.data.pop()? else {
-                    return None;
+            Command::Description | Command::Author | Command::Keywords => {
+                let text = pop!(stack, Text);
+                let name = match self {
+                    Command::Description => "description",
+                    Command::Author => "author",
+                    _ => "keywords",
                 };
-                stack.data.push(stack.scope.get(&name)?.clone())
+                stack.set_meta(name, text.content);
             }
-            Command::Store => {
-                let Value::Symbol(name) = stack.data.pop()? else {
-                    return None;
+            Command::Published => {
+                let text = pop!(stack, Text);
+                let date = if let Ok(time) = DateTime::parse_from_rfc3339(&text.content) {
+                    time
+                } else if let Ok(date) = NaiveDate::parse_from_str(&text.content, "%Y-%m-%d") {
+                    date.and_time(NaiveTime::MIN).and_utc().fixed_offset()
+                } else {
+                    return Err(ErrorKind::InvalidDate(text.content).into());
                 };
-                let value = stack.data.pop()?;
-                stack.scope.insert(name, value);
+                stack.published = Some(date);
             }
-            Command::Concat => {
-                let Value::Text(text2) = stack.data.pop()? else {
-                    return None;
+            Command::Canonical => {
+                stack.canonical = Some(pop!(stack, Link));
+            }
+            Command::Favicon => {
+                stack.favicon = Some(match pop!(stack) {
+                    Value::Link(url) => url,
+                    Value::Text(path) => stack.asset(&path.content)?,
+                    value => {
+                        return Err(ErrorKind::TypeMismatch {
+                            expected: "Link",
+                            found: value.type_name(),
+                        }
+                        .into());
+                    }
+                });
+            }
+            Command::Lang => {
+                let lang = pop!(stack, Text).content;
+                let valid = |part: &str| {
+                    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())
+                };
+                if !lang.split('-').all(valid) {
+                    return Err(ErrorKind::InvalidValue("language tag", lang).into());
+                }
+                stack.lang = Some(lang);
+            }
+            Command::TwitterCard => {
+                let card = pop!(stack, Text).content;
+                if !["summary", "summary_large_image", "app", "player"].contains(&card.as_str()) {
+                    return Err(ErrorKind::InvalidValue("Twitter card", card).into());
+                }
+                stack.twitter_card = Some(card);
+            }
+            Command::Include => {
+                let text = pop!(stack, Text);
+                let path = stack.dir.join(&text.content);
+                // Files included by several documents are read only once
+                let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+                if stack.including.contains(&key) {
+                    return Err(ErrorKind::IncludeCycle(path).into());
+                }
+                let (tokens, warnings) = match stack.includes.get(&key) {
+                    Some(tokens) => tokens.clone(),
+                    None => {
+                        let source =
+                            read_to_string(&path).map_err(|e| ErrorKind::Io(path.clone(), e))?;
+                        let mut warnings = Vec::new();
+                        let tokens = tokenize(&source, &mut warnings)
+                            .map_err(|error| ErrorKind::Include(path.clone(), Box::new(error)))?;
+                        let tokens = (tokens, warnings);
+                        stack.includes.insert(key.clone(), tokens.clone());
+                        tokens
+                    }
+                };
+                let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                let parent = std::mem::replace(&mut stack.dir, dir);
+                let count = stack.warnings.len();
+                stack.warnings.extend(warnings);
+                // A chain of includes counts as nested calls, which also limits its length
+                stack.including.push(key);
+                let result = stack.nested(|stack| run_tokens(tokens, stack));
+                stack.including.pop();
+                stack.dir = parent;
+                for warning in &mut stack.warnings[count..] {
+                    warning.path.get_or_insert_with(|| path.clone());
+                }
+                result.map_err(|error| ErrorKind::Include(path, Box::new(error)))?;
+            }
+            Command::ReadFile => {
+                let mut text = pop!(stack, Text);
+                let path = stack.dir.join(&text.content);
+                text.content = read_to_string(&path).map_err(|e| ErrorKind::Io(path, e))?;
+                stack.data.push(Value::Text(text));
+            }
+            Command::Env => {
+                let mut text = pop!(stack, Text);
+                let Ok(value) = std::env::var(&text.content) else {
+                    return Err(ErrorKind::Env(text.content).into());
+                };
+                text.content = value;
+                stack.data.push(Value::Text(text));
+            }
+            Command::Now => {
+                let now = chrono::Local::now().to_rfc3339_opts(SecondsFormat::Secs, false);
+                stack.data.push(Value::Text(Text::new(now)));
+            }
+            Command::Today => {
+                let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+                stack.data.push(Value::Text(Text::new(today)));
+            }
+            Command::DateFormat => {
+                let pattern = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                let Ok(items) = StrftimeItems::new(&pattern.content).parse() else {
+                    return Err(ErrorKind::InvalidDate(pattern.content).into());
                 };
-                let Value::Text(mut text1) = stack.data.pop()? else {
-                    return None;
+                // A pattern with fields the value lacks, like the time of a date, fails to write
+                let mut content = String::new();
+                let result = if let Ok(time) = DateTime::parse_from_rfc3339(&text.content) {
+                    write!(content, "{}", time.format_with_items(items.iter()))
+                } else if let Ok(date) = NaiveDate::parse_from_str(&text.content, "%Y-%m-%d") {
+                    write!(content, "{}", date.format_with_items(items.iter()))
+                } else {
+                    return Err(ErrorKind::InvalidDate(text.content).into());
                 };
+                if result.is_err() {
+                    return Err(ErrorKind::InvalidDate(pattern.content).into());
+                }
+                text.content = content;
+                stack.data.push(Value::Text(text));
+            }
+            Command::Load => {
+                let name = pop!(stack, Symbol);
+                let value = stack.lookup(&name)?.clone();
+                stack.data.push(value);
+            }
+            Command::Store | Command::Const => {
+                let name = pop!(stack, Symbol);
+                let value = pop!(stack);
+                if stack.constants.contains(&name) {
+                    return Err(ErrorKind::Constant(name).into());
+                }
+                if let Command::Const = self {
+                    stack.constants.insert(name.clone());
+                }
+                stack.warn(WarningKind::Unused(name.clone()));
+                stack.scope.insert(name, value);
+            }
+            Command::Defined => {
+                let name = pop!(stack, Symbol);
+                let defined = stack.scope.contains_key(&name);
+                stack.data.push(Value::Bool(defined));
+            }
+            Command::Assert => {
+                let message = pop!(stack, Text);
+                if !pop!(stack, Bool) {
+                    return Err(ErrorKind::Assertion(message.content).into());
+                }
+            }
+            Command::Error => {
+                let message = pop!(stack, Text);
+                return Err(ErrorKind::Raised(message.content).into());
+            }
+            Command::Concat => {
+                let text2 = pop!(stack, Text);
+                let mut text1 = pop!(stack, Text);
                 text1.content.push_str(&text2.content);
                 stack.data.push(Value::Text(text1));
             }
+            Command::Append => {
+                let mut span = pop!(stack, Text);
+                let mut text = pop!(stack, Text);
+                text.footnotes.append(&mut span.footnotes);
+                text.spans.push(span);
+                stack.data.push(Value::Text(text));
+            }
+            Command::Format => {
+                let mut text = pop!(stack, Text);
+                text.content = format(&text.content, stack)?;
+                stack.data.push(Value::Text(text));
+            }
+            Command::Len => {
+                let text = pop!(stack, Text);
+                let len = text.content.chars().count();
+                let len = len.try_into().map_err(|_| ErrorKind::Overflow)?;
+                stack.data.push(Value::Integer(len));
+            }
+            Command::Slice => {
+                let end = pop!(stack, Integer);
+                let start = pop!(stack, Integer);
+                let mut text = pop!(stack, Text);
+                let (Ok(from), Ok(to)) = (usize::try_from(start), usize::try_from(end)) else {
+                    return Err(ErrorKind::InvalidRange(start, end).into());
+                };
+                if from > to {
+                    return Err(ErrorKind::InvalidRange(start, end).into());
+                }
+                let len = text.content.chars().count();
+                let (from, to) = (from.min(len), to.min(len));
+                text.content = text.content.chars().skip(from).take(to - from).collect();
+                stack.data.push(Value::Text(text));
+            }
+            Command::Split => {
+                let separator = pop!(stack, Text);
+                let text = pop!(stack, Text);
+                let items = (text.content.split(&separator.content))
+                    .map(|item| Value::Text(Text::new(item.to_string())))
+                    .collect();
+                stack.data.push(Value::List(items));
+            }
+            Command::Join => {
+                let mut separator = pop!(stack, Text);
+                let items = pop!(stack, List);
+                let items: Vec<String> = items.iter().map(Value::to_string).collect();
+                separator.content = items.join(&separator.content);
+                stack.data.push(Value::Text(separator));
+            }
+            Command::Nth => {
+                let index = pop!(stack, Integer);
+                let mut items = pop!(stack, List);
+                let len = items.len() as i32;
+                let position = if index < 0 { len + index } else { index };
+                if !(0..len).contains(&position) {
+                    return Err(ErrorKind::OutOfRange(index, len).into());
+                }
+                stack.data.push(items.swap_remove(position as usize));
+            }
+            Command::Push => {
+                let value = pop!(stack);
+                let mut items = pop!(stack, List);
+                items.push(value);
+                stack.data.push(Value::List(items));
+            }
+            Command::PopItem => {
+                let mut items = pop!(stack, List);
+                let Some(value) = items.pop() else {
+                    return Err(ErrorKind::OutOfRange(-1, 0).into());
+                };
+                stack.data.push(Value::List(items));
+                stack.data.push(value);
+            }
+            Command::Count => {
+                let items = pop!(stack, List);
+                let count = items.len().try_into().map_err(|_| ErrorKind::Overflow)?;
+                stack.data.push(Value::Integer(count));
+            }
+            Command::MapNew => {
+                stack.data.push(Value::Map(BTreeMap::new()));
+            }
+            Command::MapSet => {
+                let key = pop!(stack, Symbol);
+                let value = pop!(stack);
+                let mut entries = pop!(stack, Map);
+                entries.insert(key, value);
+                stack.data.push(Value::Map(entries));
+            }
+            Command::MapGet => {
+                let key = pop!(stack, Symbol);
+                let mut entries = pop!(stack, Map);
+                let Some(value) = entries.remove(&key) else {
+                    return Err(ErrorKind::MissingKey(key).into());
+                };
+                stack.data.push(value);
+            }
             Command::Dup => {
-                let value = stack.data.pop()?;
+                let value = pop!(stack);
                 stack.data.push(value.clone());
                 stack.data.push(value);
             }
             Command::Swap => {
-                let value1 = stack.data.pop()?;
-                let value2 = stack.data.pop()?;
+                let value1 = pop!(stack);
+                let value2 = pop!(stack);
                 stack.data.push(value1);
                 stack.data.push(value2);
             }
             Command::Pop => {
-                stack.data.pop()?;
+                pop!(stack);
+            }
+            Command::Call => {
+                let body = pop!(stack, Quote);
+                stack.call(&body)?;
+            }
+            Command::Each => {
+                let body = pop!(stack, Quote);
+                let items = pop!(stack, List);
+                for item in items {
+                    stack.data.push(item);
+                    stack.call(&body)?;
+                }
+            }
+            Command::Map => {
+                let body = pop!(stack, Quote);
+                let items = pop!(stack, List);
+                let data = std::mem::take(&mut stack.data);
+                let mut result = Ok(());
+                for item in items {
+                    stack.data.push(item);
+                    result = stack.call(&body);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                let items = std::mem::replace(&mut stack.data, data);
+                result?;
+                stack.data.push(Value::List(items));
+            }
+            Command::Section
+            | Command::Div
+            | Command::PageHeader
+            | Command::PageFooter
+            | Command::Nav
+            | Command::Columns => {
+                let (classes, styles) = match self {
+                    Command::Div => (vec![pop!(stack, Text).content], Vec::new()),
+                    Command::Columns => {
+                        let count = pop!(stack, Integer);
+                        if count <= 0 {
+                            let count = count.to_string();
+                            return Err(ErrorKind::InvalidValue("column count", count).into());
+                        }
+                        let styles = vec![format!("column-count: {count}")];
+                        (vec!["columns".to_owned()], styles)
+                    }
+                    _ => (Vec::new(), Vec::new()),
+                };
+                let body = pop!(stack, Quote);
+                let children = stack.collect(&body)?;
+                let element = match self {
+                    Command::Div | Command::Columns => "div",
+                    Command::PageHeader => "header",
+                    Command::PageFooter => "footer",
+                    Command::Nav => "nav",
+                    _ => "section",
+                };
+                let text = Text {
+                    tag: HTMLTag::Group { element, children },
+                    attributes: Box::new(Attributes {
+                        classes,
+                        styles,
+                        ..Attributes::default()
+                    }),
+                    ..Text::new(String::new())
+                };
+                stack.data.push(Value::Text(text));
+            }
+            Command::Scope => {
+                let body = pop!(stack, Quote);
+                let scope = stack.scope.clone();
+                let constants = stack.constants.clone();
+                let result = stack.call(&body);
+                stack.scope = scope;
+                stack.constants = constants;
+                result?;
+            }
+            Command::Rot => {
+                let value3 = pop!(stack);
+                let value2 = pop!(stack);
+                let value1 = pop!(stack);
+                stack.data.push(value2);
+                stack.data.push(value3);
+                stack.data.push(value1);
+            }
+            Command::Over => {
+                let value2 = pop!(stack);
+                let value1 = pop!(stack);
+                stack.data.push(value1.clone());
+                stack.data.push(value2);
+                stack.data.push(value1);
+            }
+            Command::Nip => {
+                let value2 = pop!(stack);
+                pop!(stack);
+                stack.data.push(value2);
+            }
+            Command::Tuck => {
+                let value2 = pop!(stack);
+                let value1 = pop!(stack);
+                stack.data.push(value2.clone());
+                stack.data.push(value1);
+                stack.data.push(value2);
+            }
+            Command::Depth => {
+                let depth = stack
+                    .data
+                    .len()
+                    .try_into()
+                    .map_err(|_| ErrorKind::Overflow)?;
+                stack.data.push(Value::Integer(depth));
+            }
+            Command::Clear => {
+                stack.data.clear();
             }
         }
-        Some(())
+        Ok(())
     }
 
     fn parse(source: &str) -> Option<Command> {
@@ -442,17 +6225,586 @@ impl Command {
             "font-size" => Some(Command::FontSize),
             "link" => Some(Command::Link),
             "block-quote" => Some(Command::BlockQuote),
+            "quote-source" => Some(Command::QuoteSource),
             "list" => Some(Command::List),
             "image" => Some(Command::Image),
+            "image-inline" => Some(Command::ImageInline),
+            "bold" => Some(Command::Bold),
+            "italic" => Some(Command::Italic),
+            "strike" => Some(Command::Strike),
+            "code" => Some(Command::Code),
+            "sup" => Some(Command::Sup),
+            "sub" => Some(Command::Sub),
+            "kbd" => Some(Command::Kbd),
+            "mark" => Some(Command::Mark),
+            "small" => Some(Command::Small),
+            "abbr" => Some(Command::Abbr),
+            "math" => Some(Command::Math),
+            "math-block" => Some(Command::MathBlock),
+            "diagram" => Some(Command::Diagram),
+            "raw-html" => Some(Command::RawHtml),
+            "code-block" => Some(Command::CodeBlock),
+            "figure" => Some(Command::Figure),
+            "audio" => Some(Command::Audio),
+            "video" => Some(Command::Video),
+            "embed" => Some(Command::Embed),
+            "poster" => Some(Command::Poster),
+            "size" => Some(Command::Size),
+            "url" => Some(Command::Url),
+            "new-tab" => Some(Command::NewTab),
+            "rel" => Some(Command::Rel),
+            "width" => Some(Command::Width),
+            "height" => Some(Command::Height),
+            "lazy" => Some(Command::Lazy),
+            "fallback" => Some(Command::Fallback),
+            "hr" => Some(Command::Hr),
+            "br" => Some(Command::Br),
+            "slide-break" => Some(Command::SlideBreak),
+            "toc" => Some(Command::Toc),
+            "cite" => Some(Command::Cite),
+            "bibliography" => Some(Command::Bibliography),
+            "section" => Some(Command::Section),
+            "div" => Some(Command::Div),
+            "columns" => Some(Command::Columns),
+            "page-header" => Some(Command::PageHeader),
+            "page-footer" => Some(Command::PageFooter),
+            "nav" => Some(Command::Nav),
+            "details" => Some(Command::Details),
+            "footnote" => Some(Command::Footnote),
+            "class" => Some(Command::Class),
+            "id" => Some(Command::Id),
+            "attr" => Some(Command::Attr),
+            "style" => Some(Command::Style),
+            "color" => Some(Command::Color),
+            "bg-color" => Some(Command::BgColor),
+            "align" => Some(Command::Align),
+            "ordered-list" => Some(Command::OrderedList),
+            "list-start" => Some(Command::ListStart),
+            "list-depth" => Some(Command::ListDepth),
+            "task" => Some(Command::Task),
+            "def-list" => Some(Command::DefList),
+            "table-row" => Some(Command::TableRow),
+            "table-header" => Some(Command::TableHeader),
             "title" => Some(Command::Title),
             "theme" => Some(Command::Theme),
+            "description" => Some(Command::Description),
+            "author" => Some(Command::Author),
+            "keywords" => Some(Command::Keywords),
+            "published" => Some(Command::Published),
+            "canonical" => Some(Command::Canonical),
+            "twitter-card" => Some(Command::TwitterCard),
+            "favicon" => Some(Command::Favicon),
+            "lang" => Some(Command::Lang),
+            "include" => Some(Command::Include),
+            "read-file" => Some(Command::ReadFile),
+            "env" => Some(Command::Env),
+            "now" => Some(Command::Now),
+            "today" => Some(Command::Today),
+            "date-format" => Some(Command::DateFormat),
             "load" => Some(Command::Load),
             "concat" => Some(Command::Concat),
+            "append" => Some(Command::Append),
             "store" => Some(Command::Store),
+            "const" => Some(Command::Const),
+            "defined" => Some(Command::Defined),
+            "assert" => Some(Command::Assert),
+            "error" => Some(Command::Error),
+            "format" => Some(Command::Format),
+            "len" => Some(Command::Len),
+            "slice" => Some(Command::Slice),
+            "split" => Some(Command::Split),
+            "join" => Some(Command::Join),
+            "nth" => Some(Command::Nth),
+            "push" => Some(Command::Push),
+            "pop-item" => Some(Command::PopItem),
+            "count" => Some(Command::Count),
+            "map-new" => Some(Command::MapNew),
+            "map-set" => Some(Command::MapSet),
+            "map-get" => Some(Command::MapGet),
             "dup" => Some(Command::Dup),
             "swap" => Some(Command::Swap),
             "pop" => Some(Command::Pop),
+            "call" => Some(Command::Call),
+            "each" => Some(Command::Each),
+            "map" => Some(Command::Map),
+            "scope" => Some(Command::Scope),
+            "rot" => Some(Command::Rot),
+            "over" => Some(Command::Over),
+            "nip" => Some(Command::Nip),
+            "tuck" => Some(Command::Tuck),
+            "depth" => Some(Command::Depth),
+            "clear" => Some(Command::Clear),
             _ => None,
         }
     }
 }
+
+/// Type of a value as far as it is known before the evaluation
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Text,
+    Integer,
+    Bool,
+    Link,
+    Symbol,
+    Quote,
+    List,
+    Map,
+    Any,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Text => "Text",
+            Type::Integer => "Integer",
+            Type::Bool => "Bool",
+            Type::Link => "Link",
+            Type::Symbol => "Symbol",
+            Type::Quote => "Quote",
+            Type::List => "List",
+            Type::Map => "Map",
+            Type::Any => "Any",
+        }
+    }
+}
+
+/// Stack effect of a command that can be known without evaluating it
+enum Effect {
+    /// Pops values of the former types and pushes values of the latter types
+    Pure(&'static [Type], &'static [Type]),
+    /// Pops the given number of values and pushes them back in the given order
+    Shuffle(usize, &'static [usize]),
+    /// Depends on the popped values, so `Checker` handles it case by case
+    Dynamic,
+}
+
+impl Command {
+    fn effect(&self) -> Effect {
+        use Type::*;
+        match self {
+            Command::Heading | Command::FontSize => Effect::Pure(&[Text, Integer], &[Text]),
+            Command::Link => Effect::Pure(&[Text, Link], &[Text]),
+            Command::Url => Effect::Pure(&[Text], &[Link]),
+            Command::NewTab => Effect::Pure(&[Text], &[Text]),
+            Command::Rel => Effect::Pure(&[Text, Text], &[Text]),
+            Command::QuoteSource => Effect::Pure(&[Text, Any], &[Text]),
+            Command::BlockQuote | Command::List | Command::OrderedList => {
+                Effect::Pure(&[Text], &[Text])
+            }
+            Command::ListStart | Command::ListDepth | Command::Width | Command::Height => {
+                Effect::Pure(&[Text, Integer], &[Text])
+            }
+            Command::Bold
+            | Command::Italic
+            | Command::Strike
+            | Command::Code
+            | Command::Sup
+            | Command::Sub
+            | Command::Kbd
+            | Command::Mark
+            | Command::Small
+            | Command::Math
+            | Command::MathBlock
+            | Command::Diagram
+            | Command::RawHtml
+            | Command::Lazy => Effect::Pure(&[Text], &[Text]),
+            Command::CodeBlock | Command::Abbr => Effect::Pure(&[Text, Text], &[Text]),
+            Command::TableRow | Command::TableHeader => Effect::Pure(&[List], &[Text]),
+            Command::DefList => Effect::Pure(&[Text, Text], &[Text]),
+            Command::Task => Effect::Pure(&[Bool, Text], &[Text]),
+            Command::Image => Effect::Pure(&[Any], &[Text]),
+            Command::ImageInline => Effect::Pure(&[Text], &[Text]),
+            Command::Audio | Command::Video | Command::Embed => Effect::Pure(&[Link], &[Text]),
+            Command::Poster => Effect::Pure(&[Text, Link], &[Text]),
+            Command::Size => Effect::Pure(&[Text, Integer, Integer], &[Text]),
+            Command::Hr
+            | Command::Br
+            | Command::SlideBreak
+            | Command::Toc
+            | Command::Bibliography => Effect::Pure(&[], &[Text]),
+            Command::Cite => Effect::Pure(&[Text, Text], &[Text]),
+            Command::Figure
+            | Command::Fallback
+            | Command::Footnote
+            | Command::Class
+            | Command::Id
+            | Command::Style
+            | Command::Color
+            | Command::BgColor
+            | Command::Align => Effect::Pure(&[Text, Text], &[Text]),
+            Command::Attr => Effect::Pure(&[Text, Text, Text], &[Text]),
+            Command::Title
+            | Command::Theme
+            | Command::Description
+            | Command::Author
+            | Command::Keywords
+            | Command::Published
+            | Command::TwitterCard
+            | Command::Lang => Effect::Pure(&[Text], &[]),
+            Command::Canonical => Effect::Pure(&[Link], &[]),
+            Command::Favicon => Effect::Pure(&[Any], &[]),
+            Command::Concat | Command::Append => Effect::Pure(&[Text, Text], &[Text]),
+            Command::Len => Effect::Pure(&[Text], &[Integer]),
+            Command::ReadFile | Command::Env => Effect::Pure(&[Text], &[Text]),
+            Command::Now | Command::Today => Effect::Pure(&[], &[Text]),
+            Command::DateFormat => Effect::Pure(&[Text, Text], &[Text]),
+            Command::Slice => Effect::Pure(&[Text, Integer, Integer], &[Text]),
+            Command::Depth => Effect::Pure(&[], &[Integer]),
+            Command::Split => Effect::Pure(&[Text, Text], &[List]),
+            Command::Join => Effect::Pure(&[List, Text], &[Text]),
+            Command::Nth => Effect::Pure(&[List, Integer], &[Any]),
+            Command::Push => Effect::Pure(&[List, Any], &[List]),
+            Command::PopItem => Effect::Pure(&[List], &[List, Any]),
+            Command::Count => Effect::Pure(&[List], &[Integer]),
+            Command::Defined => Effect::Pure(&[Symbol], &[Bool]),
+            Command::Assert => Effect::Pure(&[Bool, Text], &[]),
+            Command::Error => Effect::Pure(&[Text], &[]),
+            Command::MapNew => Effect::Pure(&[], &[Map]),
+            Command::MapSet => Effect::Pure(&[Map, Any, Symbol], &[Map]),
+            Command::MapGet => Effect::Pure(&[Map, Symbol], &[Any]),
+            Command::Dup => Effect::Shuffle(1, &[0, 0]),
+            Command::Swap => Effect::Shuffle(2, &[1, 0]),
+            Command::Pop => Effect::Shuffle(1, &[]),
+            Command::Rot => Effect::Shuffle(3, &[1, 2, 0]),
+            Command::Over => Effect::Shuffle(2, &[0, 1, 0]),
+            Command::Nip => Effect::Shuffle(2, &[1]),
+            Command::Tuck => Effect::Shuffle(2, &[1, 0, 1]),
+            Command::Include
+            | Command::Load
+            | Command::Store
+            | Command::Const
+            | Command::Format
+            | Command::Call
+            | Command::Each
+            | Command::Map
+            | Command::Scope
+            | Command::Section
+            | Command::Div
+            | Command::PageHeader
+            | Command::PageFooter
+            | Command::Nav
+            | Command::Columns
+            | Command::Details
+            | Command::Clear => Effect::Dynamic,
+        }
+    }
+}
+
+/// Value on the stack of `Checker`, of which only the type may be known
+#[derive(Clone, Debug)]
+enum Item {
+    Value(Value),
+    Type(Type),
+}
+
+impl Item {
+    fn ty(&self) -> Type {
+        match self {
+            Item::Value(value) => value.ty(),
+            Item::Type(ty) => *ty,
+        }
+    }
+}
+
+/// Checks the stack effects of the code before evaluating it
+struct Checker {
+    data: Vec<Item>,
+    /// Whether unknown values may lie below `data`
+    open: bool,
+    scope: HashMap<String, Item>,
+    /// Nesting of the quotations being checked
+    depth: usize,
+}
+
+impl Checker {
+    /// Nesting limit beyond which quotations are treated as unknown code
+    const MAX_DEPTH: usize = 32;
+
+    fn new(stack: &Stack) -> Checker {
+        Checker {
+            data: stack.data.iter().cloned().map(Item::Value).collect(),
+            open: false,
+            scope: (stack.scope.iter())
+                .map(|(name, value)| (name.clone(), Item::Value(value.clone())))
+                .collect(),
+            depth: 0,
+        }
+    }
+
+    fn check(&mut self, exprs: &[Expr]) -> Result<(), Error> {
+        for expr in exprs {
+            self.check_node(&expr.node)
+                .map_err(|error| error.at(&expr.token))?;
+        }
+        Ok(())
+    }
+
+    fn check_node(&mut self, node: &Node) -> Result<(), Error> {
+        match node {
+            Node::Literal(value) => self.data.push(Item::Value(value.clone())),
+            Node::Raw(text) => self.data.push(Item::Value(Value::Text(text.clone()))),
+            Node::List(body) => {
+                let data = std::mem::take(&mut self.data);
+                let open = std::mem::replace(&mut self.open, false);
+                let result = self.call(body);
+                self.data = data;
+                self.open = open;
+                result?;
+                self.data.push(Item::Type(Type::List));
+            }
+            Node::Word(name) => match self.scope.get(name).cloned() {
+                Some(Item::Value(Value::Quote(body))) => self.call(&body)?,
+                // A word of unknown type may be a quotation, which would run instead
+                Some(Item::Type(Type::Quote | Type::Any)) | None => self.forget(),
+                Some(item) => self.data.push(item),
+            },
+            Node::Command(command) => match command.effect() {
+                Effect::Pure(inputs, outputs) => {
+                    self.pop(inputs)?;
+                    self.data.extend(outputs.iter().map(|ty| Item::Type(*ty)));
+                }
+                Effect::Shuffle(count, order) => {
+                    let items = self.pop(&vec![Type::Any; count])?;
+                    self.data
+                        .extend(order.iter().map(|index| items[*index].clone()));
+                }
+                Effect::Dynamic => self.check_dynamic(command)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn check_dynamic(&mut self, command: &Command) -> Result<(), Error> {
+        match command {
+            Command::Load => match self.pop_one(Type::Symbol)? {
+                Item::Value(Value::Symbol(name)) if self.scope.contains_key(&name) => {
+                    self.data.push(self.scope[&name].clone());
+                }
+                _ => self.data.push(Item::Type(Type::Any)),
+            },
+            Command::Store | Command::Const => {
+                let name = self.pop_one(Type::Symbol)?;
+                let item = self.pop_one(Type::Any)?;
+                match name {
+                    Item::Value(Value::Symbol(name)) => {
+                        self.scope.insert(name, item);
+                    }
+                    _ => self.scope.clear(),
+                }
+            }
+            Command::Format => match self.pop_one(Type::Text)? {
+                Item::Value(Value::Text(text)) if !text.content.contains("{@") => {
+                    let (_, placeholders) = parse_format(&text.content)?;
+                    let count = placeholders.iter().filter(|x| x.is_empty()).count();
+                    self.pop(&vec![Type::Any; count])?;
+                    self.data.push(Item::Type(Type::Text));
+                }
+                _ => {
+                    self.forget();
+                    self.data.push(Item::Type(Type::Text));
+                }
+            },
+            Command::Call => match self.pop_one(Type::Quote)? {
+                Item::Value(Value::Quote(body)) => self.call(&body)?,
+                _ => self.forget(),
+            },
+            Command::Each | Command::Map => {
+                let body = self.pop_one(Type::Quote)?;
+                self.pop_one(Type::List)?;
+                let data = std::mem::replace(&mut self.data, vec![Item::Type(Type::Any)]);
+                let open = std::mem::replace(&mut self.open, true);
+                let result = match body {
+                    Item::Value(Value::Quote(body)) => self.call(&body),
+                    _ => Ok(()),
+                };
+                self.data = data;
+                self.open = open;
+                result?;
+                match command {
+                    Command::Map => self.data.push(Item::Type(Type::List)),
+                    _ => self.forget(),
+                }
+            }
+            Command::Section
+            | Command::Div
+            | Command::PageHeader
+            | Command::PageFooter
+            | Command::Nav
+            | Command::Columns
+            | Command::Details => {
+                if let Command::Div | Command::Details = command {
+                    self.pop_one(Type::Text)?;
+                }
+                if let Command::Columns = command {
+                    self.pop_one(Type::Integer)?;
+                }
+                let body = self.pop_one(Type::Quote)?;
+                let data = std::mem::take(&mut self.data);
+                let open = std::mem::replace(&mut self.open, false);
+                let result = match body {
+                    Item::Value(Value::Quote(body)) => self.call(&body),
+                    _ => Ok(()),
+                };
+                self.data = data;
+                self.open = open;
+                result?;
+                self.data.push(Item::Type(Type::Text));
+            }
+            Command::Scope => match self.pop_one(Type::Quote)? {
+                Item::Value(Value::Quote(body)) => {
+                    let scope = self.scope.clone();
+                    let result = self.call(&body);
+                    self.scope = scope;
+                    result?;
+                }
+                _ => self.forget(),
+            },
+            Command::Include => {
+                self.pop_one(Type::Text)?;
+                self.forget();
+                self.scope.clear();
+            }
+            Command::Clear => {
+                self.data.clear();
+                self.open = false;
+            }
+            _ => self.forget(),
+        }
+        Ok(())
+    }
+
+    /// Check the body of a quotation as if it is inlined at the call site
+    fn call(&mut self, body: &[Expr]) -> Result<(), Error> {
+        if self.depth >= Checker::MAX_DEPTH {
+            self.forget();
+            return Ok(());
+        }
+        self.depth += 1;
+        let result = body.iter().try_for_each(|expr| self.check_node(&expr.node));
+        self.depth -= 1;
+        result
+    }
+
+    /// Give up tracking the stack after code whose effect is unknown
+    fn forget(&mut self) {
+        self.data.clear();
+        self.open = true;
+    }
+
+    fn pop(&mut self, inputs: &[Type]) -> Result<Vec<Item>, Error> {
+        let index = self.data.len().saturating_sub(inputs.len());
+        let found = &self.data[index..];
+        let matches = |(expected, item): (&Type, &Item)| {
+            *expected == Type::Any || item.ty() == Type::Any || item.ty() == *expected
+        };
+        let is_complete = found.len() == inputs.len() || self.open;
+        let offset = inputs.len() - found.len();
+        if !is_complete || !inputs[offset..].iter().zip(found).all(matches) {
+            return Err(ErrorKind::Effect {
+                expected: inputs.to_vec(),
+                found: found.iter().map(Item::ty).collect(),
+            }
+            .into());
+        }
+        let mut items = vec![Item::Type(Type::Any); offset];
+        items.extend(self.data.split_off(index));
+        Ok(items)
+    }
+
+    fn pop_one(&mut self, ty: Type) -> Result<Item, Error> {
+        Ok(self.pop(&[ty])?.remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate the source code after the prelude, as a document is
+    fn eval(source: &str) -> Result<Stack, Error> {
+        let options = Options {
+            prelude: true,
+            strict: false,
+            defines: Vec::new(),
+            viewport: true,
+            minify: false,
+            lazy_images: false,
+            new_tab: false,
+            format: Format::Html,
+            bundle: false,
+            template: None,
+            fragment: false,
+            slides: false,
+        };
+        let mut stack = prepare(&options)?;
+        run(source, &mut stack)?;
+        Ok(stack)
+    }
+
+    #[test]
+    fn date_format_rejects_missing_fields() {
+        let error = eval("\"2024-01-01\" \"%H:%M\" date-format").err().unwrap();
+        assert!(matches!(error.kind, ErrorKind::InvalidDate(ref pattern) if pattern == "%H:%M"));
+        let token = error.token.unwrap();
+        assert_eq!((token.text.as_str(), token.span.line), ("date-format", 1));
+
+        let stack = eval("\"2024-01-01\" \"%Y/%m/%d\" date-format")
+            .ok()
+            .unwrap();
+        let Some(Value::Text(text)) = stack.data.last() else {
+            panic!("no text left");
+        };
+        assert_eq!(text.content, "2024/01/01");
+    }
+
+    #[test]
+    fn recursion_is_an_error() {
+        for source in [
+            "[ loop ] @loop store loop",
+            "[ @f load call ] @f store @f load call",
+            "[ ( 1 ) [ f ] each ] @f store f",
+            "[ ( 1 ) [ f ] map ] @f store f",
+            "[ [ f ] scope ] @f store f",
+            "[ ( f ) ] @f store f",
+        ] {
+            let error = eval(source).err().unwrap();
+            assert!(matches!(error.kind, ErrorKind::Recursion), "{source}");
+            assert!(error.token.is_some());
+        }
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let path = std::env::temp_dir().join(format!("stav-cycle-{}.stav", std::process::id()));
+        let source = format!("\"{}\" include", path.display());
+        write(&path, &source).unwrap();
+        let error = eval(&source).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The outer include reports the one in the file including itself
+        let ErrorKind::Include(_, error) = error.kind else {
+            panic!("not an include error");
+        };
+        assert!(matches!(error.kind, ErrorKind::IncludeCycle(_)));
+        assert_eq!(error.token.unwrap().text, "include");
+    }
+
+    #[test]
+    fn word_of_unknown_type_may_be_a_quotation() {
+        let stack = eval("( [ \"a\" \"b\" ] ) 0 nth @f store f concat")
+            .ok()
+            .unwrap();
+        let Some(Value::Text(text)) = stack.data.last() else {
+            panic!("no text left");
+        };
+        assert_eq!(text.content, "ab");
+    }
+
+    #[test]
+    fn plain_text_indents_every_line_of_code() {
+        let source = "\"\"\"fn main() {\n    run();\n}\"\"\" \"rust\" code-block";
+        let text = plain_text(eval(source).ok().unwrap());
+        assert_eq!(text.trim_end(), "    fn main() {\n        run();\n    }");
+    }
+}